@@ -0,0 +1,283 @@
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::peer::PeerId;
+use crate::requests::request::{Event, Transfer};
+use crate::responses::response::{Deployment, SwarmStats};
+use crate::torrent::InfoHash;
+use crate::udp_tracker;
+
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often a tracker pushes an empty delta to a quiet subscription.
+pub const SUBSCRIPTION_KEEPALIVE: Duration = Duration::from_secs(5);
+
+/// A tracker, and the protocol it is spoken to with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrackerAddr {
+    /// Our length-prefixed protobuf protocol over TCP.
+    Tcp(SocketAddr),
+    /// The UDP tracker protocol of BEP 15.
+    Udp(SocketAddr),
+}
+
+impl TrackerAddr {
+    /// Parse `udp://host:port` for a UDP tracker, or a bare `host:port` for
+    /// our own protocol.
+    pub fn parse(tracker: &str) -> Option<Self> {
+        let resolve = |authority: &str| authority.to_socket_addrs().ok()?.next();
+        match tracker.strip_prefix("udp://") {
+            Some(rest) => Some(TrackerAddr::Udp(resolve(rest.split('/').next()?)?)),
+            None => Some(TrackerAddr::Tcp(resolve(tracker)?)),
+        }
+    }
+}
+
+impl fmt::Display for TrackerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackerAddr::Tcp(addr) => write!(f, "{}", addr),
+            TrackerAddr::Udp(addr) => write!(f, "udp://{}", addr),
+        }
+    }
+}
+
+/// What a peer tells a tracker about itself.
+pub struct Announce {
+    pub listening_addr: SocketAddr,
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+    /// `Started` joins the swarm.
+    pub event: Event,
+    pub transfer: Transfer,
+    /// Identifies the user to trackers with access control, empty for none.
+    pub passkey: String,
+}
+
+/// The trackers of a torrent as seen by a peer, shared between its loops.
+#[derive(Clone)]
+pub struct TrackerTiers {
+    tiers: Arc<Mutex<Vec<Vec<TrackerAddr>>>>,
+    passkey: String,
+}
+
+impl TrackerTiers {
+    pub fn new(tiers: Vec<Vec<TrackerAddr>>) -> Self {
+        TrackerTiers {
+            tiers: Arc::new(Mutex::new(tiers)),
+            passkey: String::new(),
+        }
+    }
+
+    /// Identify the user with `passkey` to trackers with access control.
+    pub fn set_passkey(&mut self, passkey: String) {
+        self.passkey = passkey;
+    }
+
+    pub fn passkey(&self) -> &str {
+        &self.passkey
+    }
+
+    /// Run `request` against one tracker of every tier and collect the answers.
+    ///
+    /// Within a tier the trackers are tried in order until one answers, and
+    /// that one is moved to the front so the next round tries it first.
+    pub fn each_tier<T, F>(&self, mut request: F) -> Vec<T>
+    where
+        F: FnMut(TrackerAddr) -> io::Result<T>,
+    {
+        let tiers = self.tiers.lock().unwrap().clone();
+        let mut answers = vec![];
+        for (tier_index, tier) in tiers.iter().enumerate() {
+            for &tracker_addr in tier {
+                match request(tracker_addr) {
+                    Ok(answer) => {
+                        answers.push(answer);
+                        self.promote(tier_index, tracker_addr);
+                        break;
+                    }
+                    Err(e) => println!("Tracker {} failed: {}", tracker_addr, e),
+                }
+            }
+        }
+        answers
+    }
+
+    fn promote(&self, tier_index: usize, tracker_addr: TrackerAddr) {
+        let mut tiers = self.tiers.lock().unwrap();
+        let tier = &mut tiers[tier_index];
+        if let Some(position) = tier.iter().position(|addr| *addr == tracker_addr) {
+            let tracker_addr = tier.remove(position);
+            tier.insert(0, tracker_addr);
+        }
+    }
+}
+
+/// Connect to a tracker without hanging on one that is down.
+pub fn connect_tracker(tracker_addr: SocketAddr) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&tracker_addr, TRACKER_TIMEOUT)?;
+    stream.set_read_timeout(Some(TRACKER_TIMEOUT))?;
+    Ok(stream)
+}
+
+/// Announce to a tracker, returning how long to wait before the next announce.
+pub fn announce(tracker: TrackerAddr, announce: &Announce) -> io::Result<Duration> {
+    match tracker {
+        TrackerAddr::Tcp(addr) => {
+            let mut stream = connect_tracker(addr)?;
+            let request = if announce.event == Event::Started {
+                crate::get_join_request(
+                    announce.listening_addr,
+                    announce.info_hash,
+                    announce.peer_id,
+                    announce.transfer.clone(),
+                    &announce.passkey,
+                )
+            } else {
+                crate::get_active_proof_request(
+                    announce.listening_addr,
+                    announce.info_hash,
+                    announce.peer_id,
+                    announce.event,
+                    announce.transfer.clone(),
+                    &announce.passkey,
+                )
+            };
+            crate::send_message(&mut stream, request)?;
+            crate::read_announce_response(&mut stream)
+        }
+        TrackerAddr::Udp(addr) => Ok(udp_tracker::announce(addr, announce, 0)?.interval),
+    }
+}
+
+/// Ask a tracker for up to `numwant` peers of the swarm. UDP trackers answer
+/// addresses only, and take the request as an announce as well.
+pub fn peer_list(
+    tracker: TrackerAddr,
+    announce: &Announce,
+    numwant: u32,
+) -> io::Result<Vec<(Option<PeerId>, SocketAddr)>> {
+    match tracker {
+        TrackerAddr::Tcp(addr) => {
+            let mut stream = connect_tracker(addr)?;
+            let request = crate::get_peer_list_request(
+                announce.info_hash,
+                announce.peer_id,
+                numwant,
+                &announce.passkey,
+            );
+            crate::send_message(&mut stream, request)?;
+            let peers = crate::read_peer_list_response(&mut stream)?;
+            Ok(peers
+                .into_iter()
+                .map(|(peer_id, addr)| (Some(peer_id), addr))
+                .collect())
+        }
+        TrackerAddr::Udp(addr) => {
+            let peers = udp_tracker::announce(addr, announce, numwant)?.peers;
+            Ok(peers.into_iter().map(|addr| (None, addr)).collect())
+        }
+    }
+}
+
+/// Statistics of the given swarms, or of every swarm when none is given. UDP
+/// trackers need the swarms named, and only report their counts.
+pub fn scrape(
+    tracker: TrackerAddr,
+    info_hashes: Vec<InfoHash>,
+    passkey: &str,
+) -> io::Result<Vec<SwarmStats>> {
+    match tracker {
+        TrackerAddr::Tcp(addr) => {
+            let mut stream = connect_tracker(addr)?;
+            crate::send_message(&mut stream, crate::get_scrape_request(info_hashes, passkey))?;
+            crate::read_scrape_response(&mut stream)
+        }
+        TrackerAddr::Udp(addr) => udp_tracker::scrape(addr, &info_hashes),
+    }
+}
+
+/// Subscribe to the joins and leaves of a swarm, returning the connection the
+/// tracker pushes them on. Only our own protocol has subscriptions.
+pub fn subscribe(
+    tracker: TrackerAddr,
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    passkey: &str,
+) -> io::Result<TcpStream> {
+    match tracker {
+        TrackerAddr::Tcp(addr) => {
+            let mut stream = connect_tracker(addr)?;
+            // a subscription missing a few keepalives is dead
+            stream.set_read_timeout(Some(SUBSCRIPTION_KEEPALIVE * 3))?;
+            crate::send_message(
+                &mut stream,
+                crate::get_subscribe_request(info_hash, peer_id, passkey),
+            )?;
+            Ok(stream)
+        }
+        TrackerAddr::Udp(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "UDP trackers have no subscriptions",
+        )),
+    }
+}
+
+/// Expect the `targets` peers, or `count` peers when there are none, to
+/// complete a swarm, replacing any deployment of it.
+pub fn deploy(
+    tracker: SocketAddr,
+    info_hash: InfoHash,
+    targets: Vec<SocketAddr>,
+    count: u32,
+    passkey: &str,
+    replace: bool,
+) -> io::Result<Deployment> {
+    let mut stream = connect_tracker(tracker)?;
+    let request = crate::get_deploy_request(info_hash, targets, count, passkey, replace);
+    crate::send_message(&mut stream, request)?;
+    crate::read_deployment_response(&mut stream)
+}
+
+pub fn deployment_status(
+    tracker: SocketAddr,
+    info_hash: InfoHash,
+    passkey: &str,
+) -> io::Result<Deployment> {
+    let mut stream = connect_tracker(tracker)?;
+    let request = crate::get_deployment_status_request(info_hash, passkey);
+    crate::send_message(&mut stream, request)?;
+    crate::read_deployment_response(&mut stream)
+}
+
+/// Store a torrent file on a tracker, under the info hash of its content.
+pub fn upload_torrent(
+    tracker: SocketAddr,
+    torrent: Vec<u8>,
+    passkey: &str,
+    admin_token: &str,
+) -> io::Result<()> {
+    let mut stream = connect_tracker(tracker)?;
+    crate::send_message(
+        &mut stream,
+        crate::get_upload_torrent_request(torrent, passkey, admin_token),
+    )?;
+    crate::read_upload_torrent_response(&mut stream)
+}
+
+/// Fetch the torrent file of a swarm from a tracker, as it was uploaded.
+/// Nothing is verified: parse it and check its info hash.
+pub fn fetch_torrent(
+    tracker: SocketAddr,
+    info_hash: InfoHash,
+    passkey: &str,
+) -> io::Result<Vec<u8>> {
+    let mut stream = connect_tracker(tracker)?;
+    crate::send_message(
+        &mut stream,
+        crate::get_fetch_torrent_request(info_hash, passkey),
+    )?;
+    crate::read_torrent_response(&mut stream)
+}
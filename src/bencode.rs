@@ -0,0 +1,319 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Lists and dictionaries nested deeper are refused, rather than
+/// overflowing the stack on hostile input.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+#[derive(Debug)]
+pub struct DecodeError {
+    pub position: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad bencode at byte {}: {}", self.position, self.reason)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_dict()?.get(key.as_bytes())
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+}
+
+/// Decode a single bencoded value which must span the whole input.
+pub fn decode(input: &[u8]) -> Result<Value, DecodeError> {
+    let mut decoder = Decoder::new(input);
+    let value = decoder.value()?;
+    decoder.end()?;
+    Ok(value)
+}
+
+/// The value of `key` in a bencoded dictionary spanning the whole input, as
+/// the bytes it is encoded with. Hashing those rather than a re-encoding
+/// keeps the hash of a value whose keys are not sorted, as other clients
+/// compute it.
+pub fn raw_dict_value<'a>(input: &'a [u8], key: &str) -> Result<Option<&'a [u8]>, DecodeError> {
+    let mut decoder = Decoder::new(input);
+    if decoder.peek()? != b'd' {
+        return Err(decoder.error("not a dictionary"));
+    }
+    decoder.position += 1;
+    let mut raw = None;
+    while decoder.peek()? != b'e' {
+        let current = decoder.bytes()?;
+        let start = decoder.position;
+        decoder.value()?;
+        if current == key.as_bytes() {
+            raw = Some(&input[start..decoder.position]);
+        }
+    }
+    decoder.position += 1;
+    decoder.end()?;
+    Ok(raw)
+}
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut output = vec![];
+    encode_into(value, &mut output);
+    output
+}
+
+fn encode_into(value: &Value, output: &mut Vec<u8>) {
+    match value {
+        Value::Integer(i) => output.extend(format!("i{}e", i).as_bytes()),
+        Value::Bytes(bytes) => {
+            output.extend(format!("{}:", bytes.len()).as_bytes());
+            output.extend(bytes);
+        }
+        Value::List(list) => {
+            output.push(b'l');
+            for item in list {
+                encode_into(item, output);
+            }
+            output.push(b'e');
+        }
+        Value::Dict(dict) => {
+            output.push(b'd');
+            for (key, item) in dict {
+                output.extend(format!("{}:", key.len()).as_bytes());
+                output.extend(key);
+                encode_into(item, output);
+            }
+            output.push(b'e');
+        }
+    }
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    position: usize,
+    depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Decoder {
+            input,
+            position: 0,
+            depth: 0,
+        }
+    }
+
+    fn end(&self) -> Result<(), DecodeError> {
+        if self.position != self.input.len() {
+            return Err(self.error("trailing data"));
+        }
+        Ok(())
+    }
+
+    fn error(&self, reason: &'static str) -> DecodeError {
+        DecodeError {
+            position: self.position,
+            reason,
+        }
+    }
+
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.input
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| self.error("unexpected end of input"))
+    }
+
+    fn value(&mut self) -> Result<Value, DecodeError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error("nested too deep"));
+        }
+        self.depth += 1;
+        let value = self.value_at_depth();
+        self.depth -= 1;
+        value
+    }
+
+    fn value_at_depth(&mut self) -> Result<Value, DecodeError> {
+        match self.peek()? {
+            b'i' => {
+                self.position += 1;
+                let integer = self.integer_until(b'e')?;
+                Ok(Value::Integer(integer))
+            }
+            b'l' => {
+                self.position += 1;
+                let mut list = vec![];
+                while self.peek()? != b'e' {
+                    list.push(self.value()?);
+                }
+                self.position += 1;
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.position += 1;
+                let mut dict = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key = self.bytes()?;
+                    let value = self.value()?;
+                    dict.insert(key, value);
+                }
+                self.position += 1;
+                Ok(Value::Dict(dict))
+            }
+            b'0'..=b'9' => Ok(Value::Bytes(self.bytes()?)),
+            _ => Err(self.error("unknown value type")),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let length = self.integer_until(b':')?;
+        if length < 0 {
+            return Err(self.error("negative string length"));
+        }
+        if length as u64 > (self.input.len() - self.position) as u64 {
+            return Err(self.error("string longer than input"));
+        }
+        let end = self.position + length as usize;
+        let bytes = self.input[self.position..end].to_vec();
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn integer_until(&mut self, terminator: u8) -> Result<i64, DecodeError> {
+        let start = self.position;
+        while self.peek()? != terminator {
+            self.position += 1;
+        }
+        let digits = std::str::from_utf8(&self.input[start..self.position])
+            .map_err(|_| self.error("bad integer"))?;
+        self.position += 1;
+        digits.parse().map_err(|_| self.error("bad integer"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dict(entries: Vec<(&str, Value)>) -> Value {
+        Value::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn round_trips() {
+        let value = dict(vec![
+            ("integer", Value::Integer(-42)),
+            ("bytes", Value::Bytes(b"\x00\xffspam".to_vec())),
+            (
+                "list",
+                Value::List(vec![
+                    Value::Integer(0),
+                    Value::Bytes(vec![]),
+                    Value::List(vec![]),
+                ]),
+            ),
+            ("dict", dict(vec![])),
+        ]);
+        let encoded = encode(&value);
+        assert_eq!(decode(&encoded).unwrap(), value);
+        assert_eq!(encode(&decode(&encoded).unwrap()), encoded);
+    }
+
+    #[test]
+    fn encodes_dictionaries_with_sorted_keys() {
+        let value = dict(vec![("b", Value::Integer(1)), ("a", Value::Integer(2))]);
+        assert_eq!(encode(&value), b"d1:ai2e1:bi1ee");
+    }
+
+    #[test]
+    fn refuses_malformed_input() {
+        let malformed: [(&[u8], &str); 10] = [
+            (b"", "unexpected end of input"),
+            (b"i42", "unexpected end of input"),
+            (b"ie", "bad integer"),
+            (b"i4x2e", "bad integer"),
+            (b"5:spam", "string longer than input"),
+            (b"-1:", "unknown value type"),
+            (b"l", "unexpected end of input"),
+            (b"di1ei2ee", "unexpected end of input"),
+            (b"x", "unknown value type"),
+            (b"i1ei2e", "trailing data"),
+        ];
+        for (input, reason) in malformed.iter() {
+            let error = decode(input).unwrap_err();
+            assert_eq!(
+                error.reason,
+                *reason,
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_deep_nesting() {
+        let mut input = vec![b'l'; MAX_DEPTH + 1];
+        input.extend(vec![b'e'; MAX_DEPTH + 1]);
+        assert_eq!(decode(&input).unwrap_err().reason, "nested too deep");
+        assert!(decode(&input[1..input.len() - 1]).is_ok());
+    }
+
+    #[test]
+    fn raw_dict_value_keeps_the_encoding() {
+        let input = b"d4:infod1:bi1e1:ai2ee4:name4:spame";
+        assert_eq!(
+            raw_dict_value(input, "info").unwrap(),
+            Some(&b"d1:bi1e1:ai2ee"[..])
+        );
+        assert_eq!(raw_dict_value(input, "missing").unwrap(), None);
+        assert!(raw_dict_value(b"li1ee", "info").is_err());
+        assert!(raw_dict_value(b"d4:infoi1ee1:x", "info").is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use p2p::announce;
+use p2p::responses::response::Deployment;
+use p2p::signing::SignaturePolicy;
+use p2p::torrent::{InfoHash, Torrent};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+fn main() {
+    let swarm_args = || {
+        vec![
+            Arg::with_name("tracker")
+                .help("tracker address")
+                .value_name("ip:port")
+                .required(true),
+            Arg::with_name("torrent")
+                .help("path of the torrent file")
+                .required(true),
+            Arg::with_name("passkey")
+                .long("passkey")
+                .help("passkey to send the tracker")
+                .value_name("key"),
+        ]
+    };
+    let app = App::new("deploy")
+        .about("push a file to a fleet of peers and follow it")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("create")
+                .about("expect peers to complete a swarm")
+                .args(&swarm_args())
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .help("listening address of a peer to reach, may be repeated")
+                        .value_name("ip:port")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required_unless("count"),
+                )
+                .arg(
+                    Arg::with_name("count")
+                        .long("count")
+                        .help("how many peers must complete, whichever they are")
+                        .value_name("count")
+                        .conflicts_with("target"),
+                )
+                .arg(
+                    Arg::with_name("replace")
+                        .long("replace")
+                        .help("replace a deployment of the swarm that is not done yet"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("print how far a deployment went")
+                .args(&swarm_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("wait")
+                .about("wait for every peer of a deployment to complete, printing the stragglers on timeout")
+                .args(&swarm_args())
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .help("seconds to wait at most")
+                        .value_name("seconds")
+                        .default_value("300"),
+                ),
+        );
+
+    let matches = app.get_matches();
+    let (command, matches) = matches.subcommand();
+    let matches = matches.unwrap();
+    let tracker: SocketAddr = matches
+        .value_of("tracker")
+        .unwrap()
+        .parse()
+        .expect("bad tracker address");
+    let info_hash = info_hash(matches);
+    let passkey = matches.value_of("passkey").unwrap_or("");
+    match command {
+        "create" => {
+            let targets = matches
+                .values_of("target")
+                .into_iter()
+                .flatten()
+                .map(|target| target.parse().expect("bad target address"))
+                .collect();
+            let count = matches.value_of("count").map_or(0, |count| {
+                count
+                    .parse()
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .expect("bad peer count")
+            });
+            let deployment = or_exit(announce::deploy(
+                tracker,
+                info_hash,
+                targets,
+                count,
+                passkey,
+                matches.is_present("replace"),
+            ));
+            println!("Deploying {} to {} peers", info_hash, deployment.expected);
+            print_progress(&deployment);
+        }
+        "status" => {
+            let deployment = or_exit(announce::deployment_status(tracker, info_hash, passkey));
+            print_progress(&deployment);
+            print_stragglers(&deployment);
+        }
+        "wait" => {
+            let timeout: f64 = matches.value_of("timeout").unwrap().parse().unwrap();
+            let deadline = Instant::now() + Duration::from_secs_f64(timeout);
+            let mut last_completed = None;
+            loop {
+                let deployment = or_exit(announce::deployment_status(tracker, info_hash, passkey));
+                if last_completed != Some(deployment.completed.len()) {
+                    print_progress(&deployment);
+                    last_completed = Some(deployment.completed.len());
+                }
+                if deployment.done {
+                    println!("Deployed {} to every peer", info_hash);
+                    return;
+                }
+                if Instant::now() >= deadline {
+                    println!("Timed out waiting for the deployment of {}", info_hash);
+                    print_stragglers(&deployment);
+                    std::process::exit(1);
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn info_hash(matches: &ArgMatches) -> InfoHash {
+    let path = Path::new(matches.value_of("torrent").unwrap());
+    match Torrent::from_file(path, &SignaturePolicy::default()) {
+        Ok(torrent) => torrent.info_hash,
+        Err(e) => {
+            eprintln!("Refusing torrent: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn or_exit(deployment: std::io::Result<Deployment>) -> Deployment {
+    deployment.unwrap_or_else(|e| {
+        eprintln!("Deployment error: {}", e);
+        std::process::exit(1);
+    })
+}
+
+fn print_progress(deployment: &Deployment) {
+    println!(
+        "{} of {} peers completed",
+        deployment.completed.len(),
+        deployment.expected
+    );
+}
+
+fn print_stragglers(deployment: &Deployment) {
+    for straggler in &deployment.stragglers {
+        println!("    straggler {}", straggler);
+    }
+}
@@ -1,45 +1,199 @@
+use p2p::announce::{self, TrackerAddr};
 use p2p::peer::Peer;
-use p2p::torrent::Torrent;
+use p2p::signing::{self, SignaturePolicy};
+use p2p::torrent::{InfoHash, Torrent};
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use clap::{App, Arg};
 
 fn main() {
     let app = App::new("peer")
         .about("run a peer")
+        .usage("peer [FLAGS] [OPTIONS] <ip:port> <torrent> [file_name] <role>")
         .arg(
             Arg::with_name("host")
-                .help("address to listen on")
+                .help("address to listen on, port 0 picks a free port")
                 .value_name("ip:port")
                 .required(true),
         )
         .arg(
             Arg::with_name("torrent")
-                .help("path of the torrent file")
+                .help("path of the torrent file, or its hex info hash with --tracker")
                 .required(true),
         )
         .arg(
-            Arg::with_name("file_name")
-                .help("output file name")
-                .required(true),
+            // the output file name was once required, before the role
+            Arg::with_name("file_name_role")
+                .help("output file name, then whether to initially run as a normal peer or seeder")
+                .value_names(&["file_name", "role"])
+                .required(true)
+                .min_values(1)
+                .max_values(2),
         )
         .arg(
-            Arg::with_name("role")
-                .help("initially run as a normal peer or seeder")
-                .required(true)
-                .possible_values(&["peer", "seeder"]),
+            Arg::with_name("output")
+                .long("output")
+                .help("output file name, defaults to the name suggested by the torrent")
+                .value_name("path"),
+        )
+        .arg(
+            Arg::with_name("tracker")
+                .long("tracker")
+                .help(
+                    "tracker to fetch the torrent file from, by its info hash, and to announce to",
+                )
+                .value_name("ip:port"),
+        )
+        .arg(
+            Arg::with_name("trusted_key")
+                .long("trusted-key")
+                .help("hex Ed25519 public key of a trusted torrent publisher")
+                .value_name("key")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .help("refuse torrents not signed by a trusted publisher"),
+        )
+        .arg(
+            Arg::with_name("announce_addr")
+                .long("announce-addr")
+                .help("address other peers reach this one at, the listening one by default")
+                .value_name("ip:port"),
+        )
+        .arg(
+            Arg::with_name("metrics_addr")
+                .long("metrics-addr")
+                .help("address to serve Prometheus metrics on")
+                .value_name("ip:port"),
+        )
+        .arg(
+            Arg::with_name("max_neighbors")
+                .long("max-neighbors")
+                .help("how many neighbors to exchange chunks with at most")
+                .value_name("count")
+                .default_value("30"),
+        )
+        .arg(
+            Arg::with_name("passkey")
+                .long("passkey")
+                .help("passkey to send the trackers")
+                .value_name("key"),
         );
 
     let matches = app.get_matches();
     let listening_addr: SocketAddr = matches.value_of("host").unwrap().parse().unwrap();
-    let torrent = Torrent::from_file(Path::new(matches.value_of("torrent").unwrap()));
-    let file_name = matches.value_of("file_name").unwrap();
-    let role = matches.value_of("role").unwrap();
+    let mut file_name_role: Vec<&str> = matches.values_of("file_name_role").unwrap().collect();
+    let role = file_name_role.pop().unwrap();
+    let output = match (file_name_role.pop(), matches.value_of("output")) {
+        (Some(_), Some(_)) => {
+            eprintln!("Give the output file name either before the role or with --output");
+            std::process::exit(1);
+        }
+        (file_name, output) => file_name.or(output),
+    };
+    if role != "peer" && role != "seeder" {
+        eprintln!("Bad role {}, expected peer or seeder", role);
+        std::process::exit(1);
+    }
+    let policy = SignaturePolicy {
+        trusted_keys: matches
+            .values_of("trusted_key")
+            .into_iter()
+            .flatten()
+            .map(|key| signing::parse_verifying_key(key).expect("bad trusted key"))
+            .collect(),
+        strict: matches.is_present("strict"),
+    };
+    let passkey = matches.value_of("passkey").unwrap_or("");
+    let torrent = matches.value_of("torrent").unwrap();
+    let torrent = match matches.value_of("tracker") {
+        Some(tracker) => fetch_torrent(
+            tracker.parse().expect("bad tracker address"),
+            InfoHash::from_hex(torrent).expect("bad info hash"),
+            passkey,
+            &policy,
+        ),
+        None => Torrent::from_file(Path::new(torrent), &policy).map_err(|e| e.to_string()),
+    };
+    let torrent = match torrent {
+        Ok(torrent) => torrent,
+        Err(e) => {
+            eprintln!("Refusing torrent: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let file_name = match output {
+        Some(file_name) => PathBuf::from(file_name),
+        None => match torrent.default_output_path() {
+            Some(file_name) => file_name,
+            None => {
+                eprintln!("The torrent suggests no file name, give one");
+                std::process::exit(1);
+            }
+        },
+    };
     let mut peer = match role {
-        "peer" => Peer::as_peer(listening_addr, torrent, Path::new(file_name)),
-        "seeder" => Peer::as_seeder(listening_addr, torrent, Path::new(file_name)),
-        _ => return,
+        "peer" => Peer::as_peer(listening_addr, torrent, &file_name),
+        "seeder" => Peer::as_seeder(listening_addr, torrent, &file_name),
+        _ => unreachable!(),
     };
+    if let Some(announce_addr) = matches.value_of("announce_addr") {
+        peer.set_announce_addr(announce_addr.parse().expect("bad announce address"));
+    }
+    if let Some(metrics_addr) = matches.value_of("metrics_addr") {
+        peer.set_metrics_addr(metrics_addr.parse().expect("bad metrics address"));
+    }
+    if !passkey.is_empty() {
+        peer.set_passkey(passkey.to_string());
+    }
+    peer.set_max_neighbors(
+        matches
+            .value_of("max_neighbors")
+            .unwrap()
+            .parse()
+            .expect("bad neighbor count"),
+    );
+    let leave_handle = peer.leave_handle();
+    ctrlc::set_handler(move || {
+        leave_handle.leave();
+        std::process::exit(0);
+    })
+    .expect("set termination handler error");
     peer.start();
 }
+
+/// Fetch the torrent file of a swarm from a tracker, which is only trusted
+/// once its info hash is the one asked for. That tracker is added as a tier
+/// of its own, so the peer keeps announcing to it besides the torrent ones.
+fn fetch_torrent(
+    tracker: SocketAddr,
+    info_hash: InfoHash,
+    passkey: &str,
+    policy: &SignaturePolicy,
+) -> Result<Torrent, String> {
+    let content = announce::fetch_torrent(tracker, info_hash, passkey)
+        .map_err(|e| format!("cannot fetch it from {}: {}", tracker, e))?;
+    let mut torrent = Torrent::from_bytes(&content, policy)
+        .map_err(|e| format!("{} sent an unusable one: {}", tracker, e))?;
+    if torrent.info_hash != info_hash {
+        return Err(format!(
+            "{} sent the torrent of swarm {}",
+            tracker, torrent.info_hash
+        ));
+    }
+    println!(
+        "Fetched the torrent of swarm {} from {}",
+        info_hash, tracker
+    );
+    let tracker = TrackerAddr::Tcp(tracker);
+    for tier in &mut torrent.trackers {
+        tier.retain(|addr| *addr != tracker);
+    }
+    torrent.trackers.retain(|tier| !tier.is_empty());
+    torrent.trackers.insert(0, vec![tracker]);
+    Ok(torrent)
+}
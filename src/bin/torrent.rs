@@ -0,0 +1,241 @@
+use p2p::announce::{self, TrackerAddr};
+use p2p::signing;
+use p2p::torrent::{InfoHash, Torrent};
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::time::SystemTime;
+
+use clap::{App, Arg, SubCommand};
+use ed25519_dalek::SigningKey;
+use sha2::{Digest, Sha256};
+
+fn main() {
+    let app = App::new("torrent")
+        .about("manage torrent files")
+        .subcommand(
+            SubCommand::with_name("create")
+                .about("create a JSON torrent for a file, printing it")
+                .arg(
+                    Arg::with_name("file")
+                        .help("path of the file to share")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("tracker")
+                        .long("tracker")
+                        .help("tracker address, each one in its own tier")
+                        .value_name("[udp://]ip:port")
+                        .required(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(Arg::with_name("comment").long("comment").takes_value(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("scrape")
+                .about("print the statistics of swarms on a tracker")
+                .arg(
+                    Arg::with_name("tracker")
+                        .help("tracker address")
+                        .value_name("[udp://]ip:port")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("info_hash")
+                        .help("hex info hashes of the swarms, every swarm when omitted")
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("passkey")
+                        .long("passkey")
+                        .help("passkey to send the tracker")
+                        .value_name("key"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("upload")
+                .about("store a torrent file on a tracker, printing its info hash")
+                .arg(
+                    Arg::with_name("tracker")
+                        .help("tracker address")
+                        .value_name("ip:port")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("torrent")
+                        .help("path of the torrent file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("passkey")
+                        .long("passkey")
+                        .help("passkey to send the tracker")
+                        .value_name("key"),
+                )
+                .arg(
+                    Arg::with_name("admin_token")
+                        .long("admin-token")
+                        .help(
+                            "admin token of the tracker, to replace a torrent of another publisher",
+                        )
+                        .value_name("token"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("keygen")
+                .about("generate a publisher key pair, printing the public key")
+                .arg(
+                    Arg::with_name("key_file")
+                        .help("where to write the secret key")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sign")
+                .about("sign a torrent file in place")
+                .arg(
+                    Arg::with_name("torrent")
+                        .help("path of the torrent file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("key_file")
+                        .help("secret key written by keygen")
+                        .required(true),
+                ),
+        );
+
+    let matches = app.get_matches();
+    match matches.subcommand() {
+        ("create", Some(matches)) => {
+            let path = Path::new(matches.value_of("file").unwrap());
+            let trackers: Vec<&str> = matches.values_of("tracker").unwrap().collect();
+            println!(
+                "{}",
+                create_torrent(path, trackers, matches.value_of("comment"))
+            );
+        }
+        ("scrape", Some(matches)) => {
+            let tracker_addr = TrackerAddr::parse(matches.value_of("tracker").unwrap())
+                .expect("bad tracker address");
+            let info_hashes = matches
+                .values_of("info_hash")
+                .into_iter()
+                .flatten()
+                .map(|info_hash| InfoHash::from_hex(info_hash).expect("bad info hash"))
+                .collect();
+            let passkey = matches.value_of("passkey").unwrap_or("");
+            let swarms = match announce::scrape(tracker_addr, info_hashes, passkey) {
+                Ok(swarms) => swarms,
+                Err(e) => {
+                    eprintln!("Scrape error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            for swarm in swarms {
+                println!(
+                    "{} seeders {} leechers {} completed {} uploaded {} downloaded {}",
+                    hex::encode(&swarm.info_hash),
+                    swarm.seeders,
+                    swarm.leechers,
+                    swarm.completed,
+                    swarm.uploaded,
+                    swarm.downloaded
+                );
+                for peer in swarm.peers {
+                    println!(
+                        "    {} at {} uploaded {} downloaded {} left {}",
+                        hex::encode(&peer.peer_id),
+                        peer.address,
+                        peer.uploaded,
+                        peer.downloaded,
+                        peer.left
+                    );
+                }
+            }
+        }
+        ("upload", Some(matches)) => {
+            let tracker = matches
+                .value_of("tracker")
+                .unwrap()
+                .parse()
+                .expect("bad tracker address");
+            let content = std::fs::read(matches.value_of("torrent").unwrap())
+                .expect("read torrent file error");
+            let info_hash = match Torrent::parse(&content) {
+                Ok(torrent) => torrent.info_hash,
+                Err(e) => {
+                    eprintln!("Refusing torrent: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let passkey = matches.value_of("passkey").unwrap_or("");
+            let admin_token = matches.value_of("admin_token").unwrap_or("");
+            if let Err(e) = announce::upload_torrent(tracker, content, passkey, admin_token) {
+                eprintln!("Upload error: {}", e);
+                std::process::exit(1);
+            }
+            println!("{}", info_hash);
+        }
+        ("keygen", Some(matches)) => {
+            let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+            let key_file = matches.value_of("key_file").unwrap();
+            // readable by its owner only, even when it replaces another file
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(key_file)
+                .expect("create key file error");
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))
+                .expect("set key file permissions error");
+            file.write_all(hex::encode(signing_key.to_bytes()).as_bytes())
+                .expect("write key file error");
+            println!("{}", hex::encode(signing_key.verifying_key().as_bytes()));
+        }
+        ("sign", Some(matches)) => {
+            let key_file = matches.value_of("key_file").unwrap();
+            let signing_key = signing::parse_signing_key(
+                &std::fs::read_to_string(key_file).expect("read key file error"),
+            )
+            .expect("bad key file");
+            let torrent = Path::new(matches.value_of("torrent").unwrap());
+            signing::sign_file(torrent, &signing_key).expect("sign torrent error");
+        }
+        _ => println!("{}", matches.usage()),
+    }
+}
+
+fn create_torrent(path: &Path, trackers: Vec<&str>, comment: Option<&str>) -> String {
+    let mut file = std::fs::File::open(path).expect("open file error");
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; p2p::CHUNK_SIZE as usize];
+    let mut file_size = 0;
+    loop {
+        let read = file.read(&mut buffer).expect("read file error");
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        file_size += read as u64;
+    }
+
+    let mut torrent = serde_json::json!({
+        "file_size": file_size,
+        "trackers": trackers,
+        "name": path.file_name().unwrap().to_string_lossy(),
+        "creation_date": SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        "created_by": concat!("p2p ", env!("CARGO_PKG_VERSION")),
+        "sha256": hex::encode(hasher.finalize()),
+    });
+    if let Some(comment) = comment {
+        torrent["comment"] = comment.into();
+    }
+    serde_json::to_string_pretty(&torrent).unwrap()
+}
@@ -1,16 +1,258 @@
 use clap::{App, Arg};
-use p2p::tracker::Tracker;
+use p2p::tracker::{Tracker, TrackerConfig};
+use std::path::PathBuf;
+use std::time::Duration;
 
 fn main() {
-    let app = App::new("tracker").about("start a tracker").arg(
-        Arg::with_name("host")
-            .help("address to listen on")
-            .value_name("IP:port")
-            .required(true),
-    );
+    let app = App::new("tracker")
+        .about("start a tracker")
+        .arg(
+            Arg::with_name("host")
+                .help("address to listen on")
+                .value_name("IP:port")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("expire")
+                .long("expire")
+                .help("seconds a peer stays listed after its last announce")
+                .value_name("seconds")
+                .default_value("5"),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .help("seconds between announces asked of peers, half the expiry by default")
+                .value_name("seconds"),
+        )
+        .arg(
+            Arg::with_name("state_file")
+                .long("state-file")
+                .help("file the swarms are saved to and restored from on restart")
+                .value_name("path"),
+        )
+        .arg(
+            Arg::with_name("trust_client_addr")
+                .long("trust-client-addr")
+                .help("list peers at the address they announce, not the one they connect from"),
+        )
+        .arg(
+            Arg::with_name("verify_peers")
+                .long("verify-peers")
+                .help("connect back to new peers and handshake before listing them"),
+        )
+        .arg(
+            Arg::with_name("admin_addr")
+                .long("admin-addr")
+                .help("address to serve the JSON status and admin endpoint on")
+                .value_name("ip:port"),
+        )
+        .arg(
+            Arg::with_name("admin_token")
+                .long("admin-token")
+                .help("bearer token enabling the admin actions, TRACKER_ADMIN_TOKEN by default")
+                .value_name("token"),
+        )
+        .arg(
+            Arg::with_name("federation_secret")
+                .long("federation-secret")
+                .help("secret the peer trackers gossip with, TRACKER_FEDERATION_SECRET by default")
+                .value_name("secret"),
+        )
+        .arg(
+            Arg::with_name("http_addr")
+                .long("http-addr")
+                .help("address to serve the HTTP tracker protocol on, for BitTorrent clients")
+                .value_name("ip:port"),
+        )
+        .arg(
+            Arg::with_name("peer_tracker")
+                .long("peer-tracker")
+                .help("another tracker to exchange swarms with, may be repeated")
+                .value_name("ip:port")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("allowlist")
+                .long("allowlist")
+                .help("file of the hex info hashes of the only torrents to track")
+                .value_name("path"),
+        )
+        .arg(
+            Arg::with_name("users")
+                .long("users")
+                .help("JSON file of users and their passkeys, required in announces")
+                .value_name("path"),
+        )
+        .arg(
+            Arg::with_name("max_requests_per_sec")
+                .long("max-requests-per-sec")
+                .help("requests an IP may make per second before it is banned")
+                .value_name("count")
+                .default_value("50"),
+        )
+        .arg(
+            Arg::with_name("max_connections")
+                .long("max-connections")
+                .help("connections served at once, of every IP")
+                .value_name("count")
+                .default_value("256"),
+        )
+        .arg(
+            Arg::with_name("max_connections_per_ip")
+                .long("max-connections-per-ip")
+                .help("connections an IP may hold open at once, subscriptions aside")
+                .value_name("count")
+                .default_value("8"),
+        )
+        .arg(
+            Arg::with_name("max_subscriptions_per_ip")
+                .long("max-subscriptions-per-ip")
+                .help("subscriptions an IP may hold open at once")
+                .value_name("count")
+                .default_value("64"),
+        )
+        .arg(
+            Arg::with_name("ban")
+                .long("ban")
+                .help("seconds an IP over its request rate is refused")
+                .value_name("seconds")
+                .default_value("60"),
+        )
+        .arg(
+            Arg::with_name("locality_group")
+                .long("locality-group")
+                .help("network whose peers are listed to each other first, may be repeated")
+                .value_name("ip/prefix")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("site_map")
+                .long("site-map")
+                .help("JSON file of the networks of each site, whose peers are listed to each other first")
+                .value_name("path"),
+        )
+        .arg(
+            Arg::with_name("remote_fraction")
+                .long("remote-fraction")
+                .help("share of a peer list kept for remote peers")
+                .value_name("fraction")
+                .default_value("0.2"),
+        )
+        .arg(
+            Arg::with_name("event_log")
+                .long("event-log")
+                .help("file to log joins, leaves, expirations, completions and rejections to, as JSON lines")
+                .value_name("path"),
+        )
+        .arg(
+            Arg::with_name("event_log_max_mb")
+                .long("event-log-max-mb")
+                .help("size in MiB at which the event log is rotated")
+                .value_name("MiB")
+                .default_value("10"),
+        )
+        .arg(
+            Arg::with_name("event_log_keep")
+                .long("event-log-keep")
+                .help("rotated event logs kept")
+                .value_name("count")
+                .default_value("5"),
+        )
+        .arg(
+            Arg::with_name("torrent_dir")
+                .long("torrent-dir")
+                .help("directory uploaded torrent files are saved to and loaded from on restart")
+                .value_name("path"),
+        )
+        .arg(
+            Arg::with_name("metrics_addr")
+                .long("metrics-addr")
+                .help("address to serve Prometheus metrics on")
+                .value_name("ip:port"),
+        );
 
     let matches = app.get_matches();
     let addr = matches.value_of("host").unwrap().parse().unwrap();
-    let mut tracker = Tracker::new();
+    let seconds = |name| {
+        let seconds: f64 = matches.value_of(name).unwrap().parse().unwrap();
+        Duration::from_secs_f64(seconds)
+    };
+    let mut config = TrackerConfig::with_expire(seconds("expire"));
+    if matches.is_present("interval") {
+        config.interval = seconds("interval");
+        config.min_interval = config.min_interval.min(config.interval);
+    }
+    config.state_file = matches.value_of("state_file").map(PathBuf::from);
+    config.trust_client_addr = matches.is_present("trust_client_addr");
+    config.verify_peers = matches.is_present("verify_peers");
+    config.admin_addr = matches
+        .value_of("admin_addr")
+        .map(|addr| addr.parse().expect("bad admin address"));
+    config.http_addr = matches
+        .value_of("http_addr")
+        .map(|addr| addr.parse().expect("bad HTTP address"));
+    config.peer_trackers = matches
+        .values_of("peer_tracker")
+        .into_iter()
+        .flatten()
+        .map(|addr| addr.parse().expect("bad peer tracker address"))
+        .collect();
+    config.allowlist_file = matches.value_of("allowlist").map(PathBuf::from);
+    config.users_file = matches.value_of("users").map(PathBuf::from);
+    config.max_requests_per_sec = matches
+        .value_of("max_requests_per_sec")
+        .unwrap()
+        .parse()
+        .expect("bad request rate");
+    let count = |name| -> usize { matches.value_of(name).unwrap().parse().expect("bad count") };
+    config.max_connections = count("max_connections");
+    config.max_connections_per_ip = count("max_connections_per_ip");
+    config.max_subscriptions_per_ip = count("max_subscriptions_per_ip");
+    config.ban = seconds("ban");
+    config.locality_groups = matches
+        .values_of("locality_group")
+        .into_iter()
+        .flatten()
+        .map(|group| group.parse().unwrap_or_else(|e| panic!("{}", e)))
+        .collect();
+    config.site_map_file = matches.value_of("site_map").map(PathBuf::from);
+    config.remote_fraction = matches
+        .value_of("remote_fraction")
+        .unwrap()
+        .parse()
+        .ok()
+        .filter(|fraction| (0.0..=1.0).contains(fraction))
+        .expect("bad remote fraction, expected one between 0 and 1");
+    config.event_log_file = matches.value_of("event_log").map(PathBuf::from);
+    let event_log_max_mb: f64 = matches
+        .value_of("event_log_max_mb")
+        .unwrap()
+        .parse()
+        .expect("bad event log size");
+    config.event_log_max_bytes = (event_log_max_mb * (1 << 20) as f64) as u64;
+    config.event_log_keep = count("event_log_keep");
+    config.torrent_dir = matches.value_of("torrent_dir").map(PathBuf::from);
+    config.metrics_addr = matches
+        .value_of("metrics_addr")
+        .map(|addr| addr.parse().expect("bad metrics address"));
+    config.admin_token = matches
+        .value_of("admin_token")
+        .map(str::to_string)
+        .or_else(|| std::env::var("TRACKER_ADMIN_TOKEN").ok());
+    config.federation_secret = matches
+        .value_of("federation_secret")
+        .map(str::to_string)
+        .or_else(|| std::env::var("TRACKER_FEDERATION_SECRET").ok());
+    let mut tracker = Tracker::with_config(config);
+    if let Some(state_saver) = tracker.state_saver() {
+        ctrlc::set_handler(move || {
+            state_saver.save().expect("save tracker state error");
+            std::process::exit(0);
+        })
+        .expect("set termination handler error");
+    }
     tracker.start(addr);
 }
@@ -0,0 +1,218 @@
+//! Just enough HTTP/1.1 for the small endpoints the binaries serve: one
+//! request per connection, no request body.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+/// Request heads longer than this are refused.
+const MAX_HEAD_LENGTH: usize = 8192;
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Query parameters, percent-decoded. Values stay bytes since some, like
+    /// info hashes, are binary.
+    pub query: Vec<(String, Vec<u8>)>,
+    /// Header names are lowercase.
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn param(&self, key: &str) -> Option<&[u8]> {
+        self.query
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    pub fn param_str(&self, key: &str) -> Option<&str> {
+        std::str::from_utf8(self.param(key)?).ok()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The non-empty segments of the path.
+    pub fn segments(&self) -> Vec<&str> {
+        self.path.split('/').filter(|s| !s.is_empty()).collect()
+    }
+}
+
+pub fn read_request(stream: &mut TcpStream) -> io::Result<Request> {
+    let invalid = |reason| io::Error::new(io::ErrorKind::InvalidData, reason);
+    let mut reader = BufReader::new(stream.take(MAX_HEAD_LENGTH as u64));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Err(invalid("bad request line")),
+    };
+
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("request head too long or cut short"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(index) = line.find(':') {
+            headers.push((
+                line[..index].trim().to_ascii_lowercase(),
+                line[index + 1..].trim().to_string(),
+            ));
+        }
+    }
+
+    let (path, query) = match target.find('?') {
+        Some(index) => (&target[..index], &target[index + 1..]),
+        None => (target.as_str(), ""),
+    };
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.find('=') {
+            Some(index) => (
+                String::from_utf8_lossy(&percent_decode(&pair[..index])).into_owned(),
+                percent_decode(&pair[index + 1..]),
+            ),
+            None => (
+                String::from_utf8_lossy(&percent_decode(pair)).into_owned(),
+                vec![],
+            ),
+        })
+        .collect();
+
+    Ok(Request {
+        method,
+        path: String::from_utf8_lossy(&percent_decode(path)).into_owned(),
+        query,
+        headers,
+    })
+}
+
+pub fn send_response(
+    stream: &mut TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        429 => "Too Many Requests",
+        _ => "Error",
+    };
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        content_type,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    stream.write_all(&response)
+}
+
+pub fn send_json(stream: &mut TcpStream, status: u16, body: &serde_json::Value) -> io::Result<()> {
+    send_response(
+        stream,
+        status,
+        "application/json",
+        body.to_string().as_bytes(),
+    )
+}
+
+/// Decode `%xx` escapes and `+` for space, keeping malformed escapes as they are.
+pub fn percent_decode(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => match hex::decode(&bytes[i + 1..i + 3]) {
+                Ok(byte) => {
+                    output.push(byte[0]);
+                    i += 3;
+                    continue;
+                }
+                Err(_) => output.push(b'%'),
+            },
+            b'+' => output.push(b' '),
+            byte => output.push(byte),
+        }
+        i += 1;
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Read what a client sends as `head`.
+    fn parse(head: &[u8]) -> io::Result<Request> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(head).unwrap();
+        drop(client);
+        read_request(&mut listener.accept().unwrap().0)
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b+c"), b"a b c");
+        assert_eq!(percent_decode("%00%FF%fe"), [0, 0xff, 0xfe]);
+        assert_eq!(percent_decode("100%"), b"100%");
+        assert_eq!(percent_decode("%zz%4"), b"%zz%4");
+    }
+
+    #[test]
+    fn announce_query() {
+        let request = parse(
+            b"GET /announce?info_hash=%01%02%ff&port=6881&compact&passkey=a+b HTTP/1.1\r\n\
+              Host: tracker\r\nAuthorization: Bearer x\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/announce");
+        assert_eq!(request.param("info_hash"), Some(&[1, 2, 0xff][..]));
+        assert_eq!(request.param_str("info_hash"), None);
+        assert_eq!(request.param_str("port"), Some("6881"));
+        assert_eq!(request.param("compact"), Some(&[][..]));
+        assert_eq!(request.param_str("passkey"), Some("a b"));
+        assert_eq!(request.param("missing"), None);
+        assert_eq!(request.header("authorization"), Some("Bearer x"));
+        assert_eq!(request.header("host"), Some("tracker"));
+    }
+
+    #[test]
+    fn path_segments() {
+        let request = parse(b"DELETE /swarms//ab/peers/cd/ HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.segments(), ["swarms", "ab", "peers", "cd"]);
+        assert!(request.query.is_empty());
+    }
+
+    #[test]
+    fn refuses_bad_heads() {
+        assert!(parse(b"GET\r\n\r\n").is_err());
+        assert!(parse(b"GET / HTTP/1.1\r\nHost: cut short").is_err());
+        let mut long = b"GET / HTTP/1.1\r\n".to_vec();
+        long.extend(vec![b'x'; MAX_HEAD_LENGTH]);
+        long.extend(b"\r\n\r\n");
+        assert!(parse(&long).is_err());
+    }
+}
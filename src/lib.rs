@@ -1,9 +1,19 @@
+pub mod announce;
+pub mod bencode;
+pub mod http;
+pub mod locality;
+pub mod metrics;
 pub mod peer;
+pub mod signing;
+pub mod storage;
 pub mod torrent;
 pub mod tracker;
+pub mod udp_tracker;
 
+use std::fmt;
 use std::io::{self, Read, Write};
 use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
 
 use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
 use bytes::{Bytes, BytesMut};
@@ -17,49 +27,202 @@ pub mod responses {
     include!(concat!(env!("OUT_DIR"), "/responses.rs"));
 }
 
+use locality::Proximity;
+use peer::PeerId;
 use requests::request;
 use requests::Request;
 use responses::response;
 use responses::Response;
+use torrent::InfoHash;
 
 type ChunkId = u64;
 pub const CHUNK_SIZE: u64 = 262144;
 
+/// Longest request accepted of each type, given the key of its field in
+/// `Request`. Most requests are small, a longer one being most likely not one
+/// of ours, but a torrent file or the gossiped state of a whole tracker can
+/// take a lot more.
+fn max_request_length(key: Option<u8>) -> u64 {
+    // the field number in requests.proto, above the 3 bits of the wire type
+    match key.map(|key| key >> 3) {
+        Some(9) => 64 << 20,  // gossip
+        Some(12) => 16 << 20, // upload_torrent
+        _ => 1 << 20,
+    }
+}
+
+/// Error of `read_request` for a request longer than its type allows, the
+/// rest of which is left unread.
+#[derive(Debug)]
+pub struct RequestTooLarge {
+    pub length: u64,
+    pub max_length: u64,
+}
+
+impl fmt::Display for RequestTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "request of {} bytes, over the limit of {} bytes",
+            self.length, self.max_length
+        )
+    }
+}
+
+impl std::error::Error for RequestTooLarge {}
+
 fn get_stream_message_length(stream: &mut TcpStream) -> io::Result<u64> {
-    Ok(stream.read_u64::<NetworkEndian>()?)
+    stream.read_u64::<NetworkEndian>()
 }
 
+/// Read a request, failing with a `RequestTooLarge` error of kind
+/// `InvalidData` when it is longer than its type allows.
 pub fn read_request(stream: &mut TcpStream) -> io::Result<Request> {
-    let message_length = get_stream_message_length(stream)?;
-    let mut buffer = vec![0; message_length as usize];
-
-    stream
-        .read_exact(&mut buffer[..])
-        .expect("stream read error");
-    let buffer = Bytes::from(buffer);
-    let request = Request::decode(buffer).expect("stream bad request message format");
-    Ok(request)
+    let length = get_stream_message_length(stream)?;
+    // a request holds a single field, its type, so the key of that field is
+    // read first to know how much of the rest to accept
+    let mut buffer = vec![0; length.min(1) as usize];
+    stream.read_exact(&mut buffer)?;
+    let max_length = max_request_length(buffer.first().copied());
+    if length > max_length {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            RequestTooLarge { length, max_length },
+        ));
+    }
+    buffer.resize(length as usize, 0);
+    stream.read_exact(&mut buffer[length.min(1) as usize..])?;
+    Request::decode(Bytes::from(buffer)).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
 
-pub fn get_join_request(listening_addr: SocketAddr) -> Request {
+pub fn get_join_request(
+    listening_addr: SocketAddr,
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    transfer: request::Transfer,
+    passkey: &str,
+) -> Request {
     let mut request = Request::default();
     request.r#type = Some(request::Type::Join(request::Join {
         listening_addr: listening_addr.to_string(),
+        info_hash: info_hash.0.to_vec(),
+        transfer: Some(transfer),
+        peer_id: peer_id.0.to_vec(),
+        passkey: passkey.to_string(),
     }));
     request
 }
 
-pub fn get_active_proof_request(addr: SocketAddr) -> Request {
+pub fn get_active_proof_request(
+    addr: SocketAddr,
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    event: request::Event,
+    transfer: request::Transfer,
+    passkey: &str,
+) -> Request {
     let mut request = Request::default();
     request.r#type = Some(request::Type::ActiveProof(request::ActiveProof {
         listening_addr: addr.to_string(),
+        info_hash: info_hash.0.to_vec(),
+        event: event as i32,
+        transfer: Some(transfer),
+        peer_id: peer_id.0.to_vec(),
+        passkey: passkey.to_string(),
+    }));
+    request
+}
+
+pub fn get_peer_list_request(
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    numwant: u32,
+    passkey: &str,
+) -> Request {
+    let mut request = Request::default();
+    request.r#type = Some(request::Type::PeerList(request::PeerList {
+        info_hash: info_hash.0.to_vec(),
+        numwant,
+        peer_id: peer_id.0.to_vec(),
+        passkey: passkey.to_string(),
+    }));
+    request
+}
+
+pub fn get_handshake_request(info_hash: InfoHash, peer_id: PeerId) -> Request {
+    let mut request = Request::default();
+    request.r#type = Some(request::Type::Handshake(request::Handshake {
+        info_hash: info_hash.0.to_vec(),
+        peer_id: peer_id.0.to_vec(),
+    }));
+    request
+}
+
+pub fn get_scrape_request(info_hashes: Vec<InfoHash>, passkey: &str) -> Request {
+    let mut request = Request::default();
+    request.r#type = Some(request::Type::Scrape(request::Scrape {
+        info_hashes: info_hashes
+            .into_iter()
+            .map(|info_hash| info_hash.0.to_vec())
+            .collect(),
+        passkey: passkey.to_string(),
+    }));
+    request
+}
+
+pub fn get_subscribe_request(info_hash: InfoHash, peer_id: PeerId, passkey: &str) -> Request {
+    let mut request = Request::default();
+    request.r#type = Some(request::Type::Subscribe(request::Subscribe {
+        info_hash: info_hash.0.to_vec(),
+        peer_id: peer_id.0.to_vec(),
+        passkey: passkey.to_string(),
+    }));
+    request
+}
+
+pub fn get_deploy_request(
+    info_hash: InfoHash,
+    targets: Vec<SocketAddr>,
+    count: u32,
+    passkey: &str,
+    replace: bool,
+) -> Request {
+    let mut request = Request::default();
+    request.r#type = Some(request::Type::Deploy(request::Deploy {
+        info_hash: info_hash.0.to_vec(),
+        targets: targets.iter().map(SocketAddr::to_string).collect(),
+        count,
+        passkey: passkey.to_string(),
+        replace,
+    }));
+    request
+}
+
+pub fn get_deployment_status_request(info_hash: InfoHash, passkey: &str) -> Request {
+    let mut request = Request::default();
+    request.r#type = Some(request::Type::DeploymentStatus(request::DeploymentStatus {
+        info_hash: info_hash.0.to_vec(),
+        passkey: passkey.to_string(),
+    }));
+    request
+}
+
+pub fn get_upload_torrent_request(torrent: Vec<u8>, passkey: &str, admin_token: &str) -> Request {
+    let mut request = Request::default();
+    request.r#type = Some(request::Type::UploadTorrent(request::UploadTorrent {
+        torrent,
+        passkey: passkey.to_string(),
+        admin_token: admin_token.to_string(),
     }));
     request
 }
 
-pub fn get_peer_list_request() -> Request {
+pub fn get_fetch_torrent_request(info_hash: InfoHash, passkey: &str) -> Request {
     let mut request = Request::default();
-    request.r#type = Some(request::Type::PeerList(request::PeerList {}));
+    request.r#type = Some(request::Type::FetchTorrent(request::FetchTorrent {
+        info_hash: info_hash.0.to_vec(),
+        passkey: passkey.to_string(),
+    }));
     request
 }
 
@@ -87,6 +250,36 @@ pub fn get_bad_response() -> Response {
     response
 }
 
+pub fn get_announce_response(interval: Duration, min_interval: Duration) -> Response {
+    let mut response = Response::default();
+    response.r#type = Some(response::Type::Announce(response::Announce {
+        interval_ms: interval.as_millis() as u64,
+        min_interval_ms: min_interval.as_millis() as u64,
+    }));
+    response
+}
+
+pub fn get_swarm_delta_response(
+    joined: Vec<(PeerId, SocketAddr, Proximity)>,
+    left: Vec<PeerId>,
+    remote_fraction: f64,
+) -> Response {
+    let mut response = Response::default();
+    response.r#type = Some(response::Type::SwarmDelta(response::SwarmDelta {
+        joined: joined
+            .into_iter()
+            .map(|(peer_id, addr, proximity)| response::PeerAddress {
+                peer_id: peer_id.0.to_vec(),
+                address: addr.to_string(),
+                proximity: proximity as i32,
+            })
+            .collect(),
+        left: left.into_iter().map(|peer_id| peer_id.0.to_vec()).collect(),
+        remote_fraction,
+    }));
+    response
+}
+
 pub fn get_chunks_query_response(chunk_ids: Vec<ChunkId>) -> Response {
     let mut response = Response::default();
     response.r#type = Some(response::Type::ChunksQuery(response::ChunksQuery {
@@ -102,28 +295,172 @@ pub fn get_fetch_chunk_response(chunk: Vec<u8>) -> Response {
     response
 }
 
+pub fn get_torrent_response(torrent: Vec<u8>) -> Response {
+    let mut response = Response::default();
+    response.r#type = Some(response::Type::Torrent(torrent));
+    response
+}
+
 pub fn read_response(stream: &mut TcpStream) -> io::Result<Response> {
     let message_length = get_stream_message_length(stream)?;
     let mut buffer = vec![0; message_length as usize];
+    stream.read_exact(&mut buffer[..])?;
+    Response::decode(Bytes::from(buffer)).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
 
-    stream
-        .read_exact(&mut buffer[..])
-        .expect("stream read error");
-    let buffer = Bytes::from(buffer);
-    let response = Response::decode(buffer).expect("stream bad response message format");
-    Ok(response)
+/// Open a connection to another peer of the swarm of `info_hash`, returning
+/// the id of that peer.
+pub fn handshake(
+    stream: &mut TcpStream,
+    info_hash: InfoHash,
+    peer_id: PeerId,
+) -> io::Result<PeerId> {
+    send_message(stream, get_handshake_request(info_hash, peer_id))?;
+    match read_response(stream)?.r#type {
+        Some(response::Type::Handshake(handshake)) => PeerId::from_bytes(&handshake.peer_id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad peer id")),
+        _ => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "handshake rejected",
+        )),
+    }
 }
 
-pub fn read_peer_list_response(stream: &mut TcpStream) -> io::Result<Vec<SocketAddr>> {
+/// Answer the handshake opening an incoming connection, returning whether the
+/// peer is in the swarm of `info_hash`.
+pub fn accept_handshake(
+    stream: &mut TcpStream,
+    info_hash: InfoHash,
+    peer_id: PeerId,
+) -> io::Result<bool> {
+    let accepted = match read_request(stream)?.r#type {
+        Some(request::Type::Handshake(handshake)) => handshake.info_hash == info_hash.0,
+        _ => false,
+    };
+    if accepted {
+        let mut response = Response::default();
+        response.r#type = Some(response::Type::Handshake(response::Handshake {
+            peer_id: peer_id.0.to_vec(),
+        }));
+        send_message(stream, response)?;
+    } else {
+        send_message(stream, get_bad_response())?;
+    }
+    Ok(accepted)
+}
+
+pub fn read_peer_list_response(stream: &mut TcpStream) -> io::Result<Vec<(PeerId, SocketAddr)>> {
     let response = read_response(stream)?;
-    if let Some(response::Type::PeerList(peers)) = response.r#type {
-        Ok(peers
-            .addresses
+    if let Some(response::Type::PeerList(peer_list)) = response.r#type {
+        peer_list
+            .peers
             .into_iter()
-            .map(|s| s.parse().unwrap())
-            .collect())
+            .map(|peer| {
+                let peer_id = PeerId::from_bytes(&peer.peer_id);
+                let addr = peer.address.parse().ok();
+                peer_id
+                    .zip(addr)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad peer address"))
+            })
+            .collect()
     } else {
-        panic!("bad peer list response from tracker");
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad peer list response from tracker",
+        ))
+    }
+}
+
+/// The peers that joined and left a swarm, as pushed to a subscriber.
+pub struct SwarmChanges {
+    /// With how close each is to the subscriber.
+    pub joined: Vec<(PeerId, SocketAddr, Proximity)>,
+    pub left: Vec<PeerId>,
+    /// Share of its neighbors the subscriber should pick among remote peers.
+    pub remote_fraction: f64,
+}
+
+pub fn read_swarm_delta(stream: &mut TcpStream) -> io::Result<SwarmChanges> {
+    let invalid = |reason| io::Error::new(io::ErrorKind::InvalidData, reason);
+    let delta = match read_response(stream)?.r#type {
+        Some(response::Type::SwarmDelta(delta)) => delta,
+        _ => return Err(invalid("subscription refused by tracker")),
+    };
+    let joined = delta
+        .joined
+        .into_iter()
+        .map(|peer| {
+            let peer_id = PeerId::from_bytes(&peer.peer_id);
+            let addr = peer.address.parse().ok();
+            let proximity = peer.proximity();
+            match (peer_id, addr) {
+                (Some(peer_id), Some(addr)) => Ok((peer_id, addr, proximity)),
+                _ => Err(invalid("bad peer address")),
+            }
+        })
+        .collect::<io::Result<_>>()?;
+    let left = delta
+        .left
+        .iter()
+        .map(|peer_id| PeerId::from_bytes(peer_id).ok_or_else(|| invalid("bad peer id")))
+        .collect::<io::Result<_>>()?;
+    Ok(SwarmChanges {
+        joined,
+        left,
+        remote_fraction: delta.remote_fraction,
+    })
+}
+
+/// Read the announce interval a tracker asks for, clamped to its minimum.
+pub fn read_announce_response(stream: &mut TcpStream) -> io::Result<Duration> {
+    match read_response(stream)?.r#type {
+        Some(response::Type::Announce(announce)) => Ok(Duration::from_millis(
+            announce.interval_ms.max(announce.min_interval_ms),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad announce response from tracker",
+        )),
+    }
+}
+
+pub fn read_scrape_response(stream: &mut TcpStream) -> io::Result<Vec<response::SwarmStats>> {
+    match read_response(stream)?.r#type {
+        Some(response::Type::Scrape(scrape)) => Ok(scrape.swarms),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad scrape response from tracker",
+        )),
+    }
+}
+
+pub fn read_deployment_response(stream: &mut TcpStream) -> io::Result<response::Deployment> {
+    match read_response(stream)?.r#type {
+        Some(response::Type::Deployment(deployment)) => Ok(deployment),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "deployment refused by tracker, or unknown to it",
+        )),
+    }
+}
+
+pub fn read_upload_torrent_response(stream: &mut TcpStream) -> io::Result<()> {
+    match read_response(stream)?.r#type {
+        Some(response::Type::Ok(_)) => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "torrent refused by tracker",
+        )),
+    }
+}
+
+pub fn read_torrent_response(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    match read_response(stream)?.r#type {
+        Some(response::Type::Torrent(torrent)) => Ok(torrent),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no such torrent on tracker",
+        )),
     }
 }
 
@@ -142,11 +479,14 @@ pub fn read_fetch_chunk_response(stream: &mut TcpStream) -> io::Result<Vec<u8>>
     panic!("we don't handle unexpecting error");
 }
 
-fn send_message<T: Message>(stream: &mut TcpStream, message: T) -> io::Result<()> {
+pub fn send_message<T: Message>(stream: &mut TcpStream, message: T) -> io::Result<()> {
     let mut message_bytes = BytesMut::new();
     message.encode(&mut message_bytes).unwrap();
-    stream.write_u64::<NetworkEndian>(message_bytes.len() as u64)?;
-    stream.write_all(message_bytes.as_ref())?;
+    // a single write, so that the length prefix is not held back by Nagle's algorithm
+    let mut frame = Vec::with_capacity(8 + message_bytes.len());
+    frame.write_u64::<NetworkEndian>(message_bytes.len() as u64)?;
+    frame.extend_from_slice(message_bytes.as_ref());
+    stream.write_all(&frame)?;
 
     Ok(())
 }
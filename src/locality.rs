@@ -0,0 +1,251 @@
+//! How close peers are to each other on the network, so that they exchange
+//! chunks with their neighbors on the LAN rather than across the WAN.
+//!
+//! Two peers are on the same subnet when their IPv4 addresses share their
+//! first 24 bits, or their IPv6 ones their first 64. They are on the same
+//! site when both are in one of the CIDR groups of the tracker, or in the
+//! networks of one site of its site map. Any other peer is remote.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use rand::seq::SliceRandom;
+use serde_json::Value;
+
+pub use crate::responses::response::Proximity;
+
+/// A network, such as `10.1.0.0/16`.
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        same_prefix(self.addr, ip, self.prefix)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("bad network {}, expected ip/prefix", s);
+        let (addr, prefix) = s.split_once('/').ok_or_else(bad)?;
+        let addr: IpAddr = addr.parse().map_err(|_| bad())?;
+        let prefix: u8 = prefix.parse().map_err(|_| bad())?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > bits {
+            return Err(bad());
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Whether two addresses of the same family share their first `prefix` bits.
+fn same_prefix(a: IpAddr, b: IpAddr, prefix: u8) -> bool {
+    let (a, b, bits) = match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => (u32::from(a) as u128, u32::from(b) as u128, 32),
+        (IpAddr::V6(a), IpAddr::V6(b)) => (u128::from(a), u128::from(b), 128),
+        _ => return false,
+    };
+    let shift = bits - prefix as u32;
+    shift >= bits || a >> shift == b >> shift
+}
+
+/// Where the peers of a tracker are, as told by its operator.
+#[derive(Default)]
+pub struct Locality {
+    groups: Vec<Cidr>,
+    /// The networks of each site, by site name.
+    sites: Vec<(String, Vec<Cidr>)>,
+}
+
+impl Locality {
+    /// The site map is a JSON object mapping each site name to the list of
+    /// its networks.
+    pub fn new(groups: Vec<Cidr>, site_map: Option<&Path>) -> Self {
+        let sites = site_map
+            .map(|path| {
+                let text = std::fs::read(path).expect("read site map error");
+                let sites: Value = serde_json::from_slice(&text).expect("bad site map");
+                let sites: Vec<(String, Vec<Cidr>)> = sites
+                    .as_object()
+                    .expect("bad site map")
+                    .iter()
+                    .map(|(site, networks)| {
+                        let networks = networks
+                            .as_array()
+                            .expect("bad site map")
+                            .iter()
+                            .map(|network| {
+                                let network = network.as_str().expect("bad site map");
+                                network.parse().unwrap_or_else(|e| panic!("{}", e))
+                            })
+                            .collect();
+                        (site.clone(), networks)
+                    })
+                    .collect();
+                println!("Loaded {} sites from {}", sites.len(), path.display());
+                sites
+            })
+            .unwrap_or_default();
+        Locality { groups, sites }
+    }
+
+    pub fn proximity(&self, a: IpAddr, b: IpAddr) -> Proximity {
+        let subnet = if a.is_ipv4() { 24 } else { 64 };
+        if same_prefix(a, b, subnet) {
+            return Proximity::Subnet;
+        }
+        let same_group = self
+            .groups
+            .iter()
+            .any(|group| group.contains(a) && group.contains(b));
+        let same_site = self.sites.iter().any(|(_, networks)| {
+            networks.iter().any(|network| network.contains(a))
+                && networks.iter().any(|network| network.contains(b))
+        });
+        if same_group || same_site {
+            Proximity::Site
+        } else {
+            Proximity::Remote
+        }
+    }
+}
+
+/// Up to `wanted` of the candidates, the closest first, in random order
+/// among equally close ones. A `remote_fraction` of them is kept for remote
+/// candidates when there are any, so that a swarm split across sites stays
+/// connected.
+pub fn pick<T>(mut candidates: Vec<(Proximity, T)>, wanted: usize, remote_fraction: f64) -> Vec<T> {
+    candidates.shuffle(&mut rand::thread_rng());
+    candidates.sort_by_key(|(proximity, _)| std::cmp::Reverse(*proximity));
+    if candidates.len() <= wanted {
+        return candidates
+            .into_iter()
+            .map(|(_, candidate)| candidate)
+            .collect();
+    }
+
+    let (near, remote): (Vec<_>, Vec<_>) = candidates
+        .into_iter()
+        .partition(|(proximity, _)| *proximity != Proximity::Remote);
+    let reserved = ((wanted as f64 * remote_fraction) as usize).min(remote.len());
+    let near_count = near.len().min(wanted - reserved);
+    near.into_iter()
+        .take(near_count)
+        .chain(remote.into_iter().take(wanted - near_count))
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn prefixes() {
+        assert!(same_prefix(ip("10.1.2.3"), ip("10.1.2.200"), 24));
+        assert!(!same_prefix(ip("10.1.2.3"), ip("10.1.3.3"), 24));
+        assert!(same_prefix(ip("10.1.2.3"), ip("10.1.2.3"), 32));
+        assert!(!same_prefix(ip("10.1.2.3"), ip("10.1.2.4"), 32));
+        assert!(same_prefix(ip("10.1.2.3"), ip("192.168.0.1"), 0));
+        assert!(same_prefix(ip("2001:db8::1"), ip("2001:db8::ffff"), 64));
+        assert!(same_prefix(ip("::1"), ip("ff02::1"), 0));
+        assert!(!same_prefix(ip("::1"), ip("::2"), 128));
+        // families never match, not even on no bits
+        assert!(!same_prefix(ip("10.1.2.3"), ip("::ffff:10.1.2.3"), 0));
+        assert!(!same_prefix(ip("::"), ip("0.0.0.0"), 0));
+    }
+
+    #[test]
+    fn parses_networks() {
+        let network: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert_eq!(network.to_string(), "10.1.0.0/16");
+        assert!(network.contains(ip("10.1.255.1")));
+        assert!(!network.contains(ip("10.2.0.1")));
+
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("203.0.113.9")));
+        assert!(!everything.contains(ip("2001:db8::1")));
+        let host: Cidr = "10.1.2.3/32".parse().unwrap();
+        assert!(host.contains(ip("10.1.2.3")));
+        assert!(!host.contains(ip("10.1.2.4")));
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(ip("2001:db8:1::1")));
+        assert!(!v6.contains(ip("10.1.2.3")));
+        assert!("::/0".parse::<Cidr>().is_ok());
+        assert!("::1/128".parse::<Cidr>().is_ok());
+
+        for bad in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0",
+            "10.0.0.0/",
+            "host/8",
+            "10.0.0.0/-1",
+        ]
+        .iter()
+        {
+            assert!(bad.parse::<Cidr>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn proximity_by_subnet_then_site() {
+        let locality = Locality::new(vec!["10.0.0.0/8".parse().unwrap()], None);
+        assert_eq!(
+            locality.proximity(ip("10.1.2.3"), ip("10.1.2.4")),
+            Proximity::Subnet
+        );
+        assert_eq!(
+            locality.proximity(ip("10.1.2.3"), ip("10.9.0.1")),
+            Proximity::Site
+        );
+        assert_eq!(
+            locality.proximity(ip("10.1.2.3"), ip("192.168.0.1")),
+            Proximity::Remote
+        );
+        assert_eq!(
+            locality.proximity(ip("10.1.2.3"), ip("::ffff:10.1.2.3")),
+            Proximity::Remote
+        );
+    }
+
+    #[test]
+    fn picks_the_closest_keeping_some_remote() {
+        let candidates = vec![
+            (Proximity::Remote, 1),
+            (Proximity::Site, 2),
+            (Proximity::Subnet, 3),
+            (Proximity::Remote, 4),
+            (Proximity::Subnet, 5),
+        ];
+        let mut picked = pick(candidates.clone(), 3, 0.0);
+        picked.sort();
+        assert_eq!(picked, [2, 3, 5]);
+
+        let picked = pick(candidates.clone(), 3, 0.5);
+        assert_eq!(picked.len(), 3);
+        assert!(picked[..2]
+            .iter()
+            .all(|candidate| [3, 5].contains(candidate)));
+        assert!([1, 4].contains(&picked[2]));
+
+        assert_eq!(pick(candidates, 10, 0.5).len(), 5);
+    }
+}
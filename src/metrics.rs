@@ -0,0 +1,120 @@
+//! Prometheus text exposition of the counters the binaries keep.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::http;
+
+/// Counters sharing a name, one per label value.
+#[derive(Default)]
+pub struct LabeledCounter {
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl LabeledCounter {
+    pub fn inc(&self, label: &str) {
+        *self
+            .values
+            .lock()
+            .unwrap()
+            .entry(label.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn values(&self) -> Vec<(String, u64)> {
+        let values = self.values.lock().unwrap();
+        values.iter().map(|(k, v)| (k.clone(), *v)).collect()
+    }
+}
+
+/// Metrics rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, "counter", help);
+        writeln!(self.text, "{} {}", name, value).unwrap();
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, "gauge", help);
+        writeln!(self.text, "{} {}", name, value).unwrap();
+    }
+
+    /// One metric per label value, `kind` being "counter" or "gauge".
+    pub fn labeled(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        label: &str,
+        values: Vec<(String, u64)>,
+    ) {
+        self.header(name, kind, help);
+        for (value_label, value) in values {
+            writeln!(
+                self.text,
+                "{}{{{}=\"{}\"}} {}",
+                name,
+                label,
+                escape(&value_label),
+                value
+            )
+            .unwrap();
+        }
+    }
+
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.text, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.text, "# TYPE {} {}", name, kind).unwrap();
+    }
+
+    pub fn into_text(self) -> String {
+        self.text
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve `GET /metrics` at `addr` on its own thread, rendering the metrics
+/// afresh for every scrape.
+pub fn serve<F>(addr: SocketAddr, render: F)
+where
+    F: Fn() -> Exposition + Send + 'static,
+{
+    let listener = TcpListener::bind(addr)
+        .unwrap_or_else(|_| panic!("metrics listener cannot bind at {}", addr));
+    println!("Serving metrics on {}", addr);
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().filter_map(|x| x.ok()) {
+            stream.set_read_timeout(Some(Duration::from_secs(1))).ok();
+            let request = match http::read_request(&mut stream) {
+                Ok(request) => request,
+                Err(_) => continue,
+            };
+            if request.method == "GET" && request.path == "/metrics" {
+                let text = render().into_text();
+                http::send_response(
+                    &mut stream,
+                    200,
+                    "text/plain; version=0.0.4",
+                    text.as_bytes(),
+                )
+                .ok();
+            } else {
+                http::send_response(&mut stream, 404, "text/plain", b"not found\n").ok();
+            }
+        }
+    });
+}
@@ -1,128 +1,376 @@
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::Write;
+use std::fmt;
+use std::io;
 use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::torrent::Torrent;
+use crate::announce::{self, Announce, TrackerTiers};
+use crate::locality::{self, Proximity};
+use crate::metrics::Exposition;
+use crate::requests::request::{Event, Transfer};
+use crate::storage::Storage;
+use crate::torrent::{InfoHash, Torrent};
 
 use threadpool::ThreadPool;
 
 type ChunkId = u64;
 
-pub struct Peer {
+/// Identifies a peer for one session, whatever address it is reached at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PeerId(pub [u8; 20]);
+
+impl PeerId {
+    pub fn random() -> Self {
+        PeerId(rand::random())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut peer_id = [0; 20];
+        if bytes.len() != peer_id.len() {
+            return None;
+        }
+        peer_id.copy_from_slice(bytes);
+        Some(PeerId(peer_id))
+    }
+
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        PeerId::from_bytes(&hex::decode(hex).ok()?)
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+struct Neighbor {
     addr: SocketAddr,
-    torrent: Torrent,
+    /// Chunks it had when last asked.
+    chunks: Vec<ChunkId>,
+}
+
+type Neighbors = Arc<Mutex<HashMap<PeerId, Neighbor>>>;
+
+/// The peers of the swarm as pushed by the trackers the peer subscribed to.
+#[derive(Default)]
+struct SwarmView {
+    /// With how close each is, as told by the trackers.
+    peers: Mutex<HashMap<PeerId, (SocketAddr, Proximity)>>,
+    /// Live subscriptions. Without one, the peer polls for peer lists.
+    subscriptions: AtomicUsize,
+    /// Share of neighbors the trackers want picked among remote peers.
+    remote_fraction: Mutex<f64>,
+}
+
+const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_millis(2500);
+const DEFAULT_MAX_NEIGHBORS: usize = 30;
+const NEIGHBOR_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a download that failed its SHA-256 check waits before it is
+/// fetched again, so that a swarm serving bad content is not hammered.
+const REFETCH_DELAY: Duration = Duration::from_secs(10);
+
+/// What the peer tells the trackers about its download.
+struct Progress {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+    /// The download just completed and the trackers have not been told yet.
+    completed_event_pending: AtomicBool,
+    /// No chunk is fetched before then, the download having failed its check.
+    refetch_after: Mutex<Option<Instant>>,
+}
+
+impl Progress {
+    fn new(left: u64) -> Self {
+        Progress {
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            left: AtomicU64::new(left),
+            completed_event_pending: AtomicBool::new(false),
+            refetch_after: Mutex::new(None),
+        }
+    }
+
+    fn refetch_pending(&self) -> bool {
+        self.refetch_after
+            .lock()
+            .unwrap()
+            .is_some_and(|after| Instant::now() < after)
+    }
+
+    fn transfer(&self) -> Transfer {
+        Transfer {
+            uploaded: self.uploaded.load(Ordering::SeqCst),
+            downloaded: self.downloaded.load(Ordering::SeqCst),
+            left: self.left.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Counters behind the peer metrics, next to those of `Progress`.
+#[derive(Default)]
+struct PeerMetrics {
+    chunks_fetched: AtomicU64,
+    /// Fetches that failed on the way, the neighbor being dropped.
+    failed_fetches: AtomicU64,
+    /// Chunks that did not match their piece hash.
+    verification_failures: AtomicU64,
+}
+
+/// What a peer tells its trackers about itself, shared by the loops announcing
+/// to them.
+#[derive(Clone)]
+struct Announcer {
+    trackers: TrackerTiers,
+    listening_addr: SocketAddr,
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    progress: Arc<Progress>,
+}
+
+impl Announcer {
+    fn query(&self, event: Event) -> Announce {
+        Announce {
+            listening_addr: self.listening_addr,
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+            event,
+            transfer: self.progress.transfer(),
+            passkey: self.trackers.passkey().to_string(),
+        }
+    }
+}
+
+/// Tells the trackers that a peer is leaving, from outside of its loops.
+pub struct LeaveHandle {
+    announcer: Announcer,
+}
+
+impl LeaveHandle {
+    pub fn leave(&self) {
+        println!("Leaving the swarm");
+        let stopped = self.announcer.query(Event::Stopped);
+        self.announcer
+            .trackers
+            .each_tier(|tracker| announce::announce(tracker, &stopped));
+    }
+}
+
+/// The state the chunk fetching loops share with the peer.
+#[derive(Clone)]
+struct ChunkFetcher {
+    neighbors: Neighbors,
+    downloaded_chunks: Arc<Mutex<Vec<ChunkId>>>,
+    storage: Arc<Mutex<Storage>>,
+    torrent: Arc<Torrent>,
+    peer_id: PeerId,
+    progress: Arc<Progress>,
+    metrics: Arc<PeerMetrics>,
+}
+
+pub struct Peer {
+    listener: TcpListener,
+    /// Where other peers reach this one, told to the trackers.
+    announce_addr: SocketAddr,
+    peer_id: PeerId,
+    torrent: Arc<Torrent>,
+    trackers: TrackerTiers,
     thread_pool: ThreadPool,
-    neighbors: Arc<Mutex<HashMap<SocketAddr, Vec<ChunkId>>>>,
+    neighbors: Neighbors,
+    swarm_view: Arc<SwarmView>,
     downloaded_chunks: Arc<Mutex<Vec<ChunkId>>>,
-    file: Arc<Mutex<File>>,
+    storage: Arc<Mutex<Storage>>,
+    progress: Arc<Progress>,
+    max_neighbors: usize,
+    metrics: Arc<PeerMetrics>,
+    metrics_addr: Option<SocketAddr>,
 }
 
 impl Peer {
     pub fn as_peer(addr: SocketAddr, torrent: Torrent, file_name: &Path) -> Self {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(file_name)
-            .expect("open file error");
-
-        file.set_len(torrent.file_size).unwrap();
+        let storage = Storage::create(file_name, &torrent);
+        let file_size = torrent.file_size;
+        let listener = TcpListener::bind(addr).expect("tcp listener bind error");
 
         Peer {
-            addr,
-            file: Arc::new(Mutex::new(file)),
-            torrent,
+            announce_addr: listener.local_addr().unwrap(),
+            listener,
+            peer_id: PeerId::random(),
+            storage: Arc::new(Mutex::new(storage)),
+            trackers: TrackerTiers::new(torrent.trackers.clone()),
+            torrent: Arc::new(torrent),
             thread_pool: ThreadPool::new(8),
             neighbors: Arc::new(Mutex::new(HashMap::new())),
+            swarm_view: Arc::new(SwarmView::default()),
             downloaded_chunks: Arc::new(Mutex::new(vec![])),
+            progress: Arc::new(Progress::new(file_size)),
+            max_neighbors: DEFAULT_MAX_NEIGHBORS,
+            metrics: Arc::new(PeerMetrics::default()),
+            metrics_addr: None,
         }
     }
 
     pub fn as_seeder(addr: SocketAddr, torrent: Torrent, file_name: &Path) -> Self {
-        use crate::CHUNK_SIZE;
-
-        let file = OpenOptions::new()
-            .read(true)
-            .open(file_name)
-            .expect("open file error");
-
-        let mut downloaded_chunks = vec![];
-        let mut chunk_id = 0;
-        while chunk_id < torrent.file_size {
-            downloaded_chunks.push(chunk_id);
-            chunk_id += CHUNK_SIZE;
-        }
+        let storage = Storage::open(file_name, &torrent);
+        let downloaded_chunks = torrent.chunk_ids();
+        let listener = TcpListener::bind(addr).expect("tcp listener bind error");
 
         Peer {
-            addr,
-            file: Arc::new(Mutex::new(file)),
-            torrent,
+            announce_addr: listener.local_addr().unwrap(),
+            listener,
+            peer_id: PeerId::random(),
+            storage: Arc::new(Mutex::new(storage)),
+            trackers: TrackerTiers::new(torrent.trackers.clone()),
+            torrent: Arc::new(torrent),
             thread_pool: ThreadPool::new(8),
             neighbors: Arc::new(Mutex::new(HashMap::new())),
+            swarm_view: Arc::new(SwarmView::default()),
             downloaded_chunks: Arc::new(Mutex::new(downloaded_chunks)),
+            progress: Arc::new(Progress::new(0)),
+            max_neighbors: DEFAULT_MAX_NEIGHBORS,
+            metrics: Arc::new(PeerMetrics::default()),
+            metrics_addr: None,
         }
     }
 
+    /// Announce another address than the one listened on, for a peer behind
+    /// NAT or listening on every interface. Port 0 keeps the bound port.
+    pub fn set_announce_addr(&mut self, mut announce_addr: SocketAddr) {
+        if announce_addr.port() == 0 {
+            announce_addr.set_port(self.announce_addr.port());
+        }
+        self.announce_addr = announce_addr;
+    }
+
+    /// Serve Prometheus metrics at `metrics_addr` once started.
+    pub fn set_metrics_addr(&mut self, metrics_addr: SocketAddr) {
+        self.metrics_addr = Some(metrics_addr);
+    }
+
+    /// Identify the user with `passkey` in every tracker request.
+    pub fn set_passkey(&mut self, passkey: String) {
+        self.trackers.set_passkey(passkey);
+    }
+
+    /// How many neighbors the peer exchanges chunks with at most.
+    pub fn set_max_neighbors(&mut self, max_neighbors: usize) {
+        self.max_neighbors = max_neighbors;
+    }
+
     pub fn start(&mut self) {
-        let listener = TcpListener::bind(self.addr).expect("tcp listener bind error");
-        println!("Start listening at {}", self.addr);
-        self.join_the_swarm(listener.local_addr().unwrap());
+        println!(
+            "Start listening at {}, announced as {}",
+            self.listener.local_addr().unwrap(),
+            self.announce_addr
+        );
+        if let Some(metrics_addr) = self.metrics_addr {
+            let progress = Arc::clone(&self.progress);
+            let neighbors = Arc::clone(&self.neighbors);
+            let metrics = Arc::clone(&self.metrics);
+            crate::metrics::serve(metrics_addr, move || {
+                render_metrics(&progress, &neighbors, &metrics)
+            });
+        }
+        let announce_interval = self.join_the_swarm();
 
-        let tracker_addr = self.torrent.tracker_addr;
-        let listening_addr = self.addr;
-        std::thread::spawn(move || active_proof_loop(tracker_addr, listening_addr));
+        let info_hash = self.torrent.info_hash;
+        let peer_id = self.peer_id;
+        let announcer = self.announcer();
+        std::thread::spawn(move || active_proof_loop(announcer, announce_interval));
+        let trackers = self.trackers.clone();
         let neighbors = Arc::clone(&self.neighbors);
-        std::thread::spawn(move || update_neighbors_loop(tracker_addr, listening_addr, neighbors));
+        let swarm_view = Arc::clone(&self.swarm_view);
+        let max_neighbors = self.max_neighbors;
+        std::thread::spawn(move || {
+            subscription_loop(
+                trackers,
+                info_hash,
+                peer_id,
+                swarm_view,
+                neighbors,
+                max_neighbors,
+            )
+        });
+        let announcer = self.announcer();
         let neighbors = Arc::clone(&self.neighbors);
-        std::thread::spawn(move || update_downloaded_chunks_loop(neighbors));
-
+        let swarm_view = Arc::clone(&self.swarm_view);
+        let max_neighbors = self.max_neighbors;
+        std::thread::spawn(move || {
+            update_neighbors_loop(announcer, neighbors, swarm_view, max_neighbors)
+        });
+        let neighbors = Arc::clone(&self.neighbors);
+        let torrent = Arc::clone(&self.torrent);
+        std::thread::spawn(move || update_downloaded_chunks_loop(neighbors, torrent, peer_id));
+
+        let fetcher = ChunkFetcher {
+            neighbors: Arc::clone(&self.neighbors),
+            downloaded_chunks: Arc::clone(&self.downloaded_chunks),
+            storage: Arc::clone(&self.storage),
+            torrent: Arc::clone(&self.torrent),
+            peer_id,
+            progress: Arc::clone(&self.progress),
+            metrics: Arc::clone(&self.metrics),
+        };
         for _ in 0..8 {
-            let neighbors = Arc::clone(&self.neighbors);
-            let downloaded_chunks = Arc::clone(&self.downloaded_chunks);
-            let file = Arc::clone(&self.file);
-            let file_size = self.torrent.file_size;
-            self.thread_pool
-                .execute(move || fetch_chunk_loop(neighbors, downloaded_chunks, file, file_size));
+            let fetcher = fetcher.clone();
+            self.thread_pool.execute(move || fetch_chunk_loop(fetcher));
         }
 
+        let listener = self.listener.try_clone().expect("tcp listener clone error");
         for stream in listener.incoming().filter_map(|x| x.ok()) {
             self.handle_peer(stream);
         }
     }
 
-    fn join_the_swarm(&mut self, listening_addr: SocketAddr) {
+    pub fn leave_handle(&self) -> LeaveHandle {
+        LeaveHandle {
+            announcer: self.announcer(),
+        }
+    }
+
+    fn announcer(&self) -> Announcer {
+        Announcer {
+            trackers: self.trackers.clone(),
+            listening_addr: self.announce_addr,
+            info_hash: self.torrent.info_hash,
+            peer_id: self.peer_id,
+            progress: Arc::clone(&self.progress),
+        }
+    }
+
+    /// Join the swarm on every tier, returning when to announce next.
+    fn join_the_swarm(&mut self) -> Duration {
         println!("Attempt to join the swarm");
-        let mut stream =
-            TcpStream::connect(self.torrent.tracker_addr).expect("stream connect tracker error");
-        let message = crate::get_join_request(listening_addr);
-        crate::send_message(&mut stream, message).unwrap();
-        crate::read_response(&mut stream).unwrap();
+        let started = self.announcer().query(Event::Started);
+        let intervals = self
+            .trackers
+            .each_tier(|tracker| announce::announce(tracker, &started));
+        if intervals.is_empty() {
+            println!("No tracker reachable, will keep announcing");
+        }
+        next_announce_interval(intervals)
     }
 
     fn get_local_chunk(&self, chunk_id: ChunkId) -> Vec<u8> {
-        use crate::CHUNK_SIZE;
-        use std::cmp::min;
-
-        let start_position = chunk_id as usize;
-        let end_position = min(
-            start_position + CHUNK_SIZE as usize,
-            self.torrent.file_size as usize,
-        );
-        let file = self.file.lock().unwrap();
-        let map = unsafe { memmap::Mmap::map(&file).unwrap() };
-        (&map[start_position..end_position])
-            .iter()
-            .cloned()
-            .collect()
+        let length = chunk_length(chunk_id, &self.torrent);
+        self.storage.lock().unwrap().read(chunk_id, length)
     }
 
     fn handle_peer(&mut self, mut stream: TcpStream) {
         use crate::requests::request::Type;
+        match crate::accept_handshake(&mut stream, self.torrent.info_hash, self.peer_id) {
+            Ok(true) => {}
+            Ok(false) => {
+                println!("Rejecting a peer of another swarm");
+                return;
+            }
+            Err(_) => return,
+        }
+
         let request = if let Ok(request) = crate::read_request(&mut stream) {
             request
         } else {
@@ -149,45 +397,114 @@ impl Peer {
     }
 
     fn handle_fetch_chunk_request(&mut self, stream: &mut TcpStream, chunk_id: ChunkId) {
+        if !self.torrent.is_chunk_id(chunk_id) {
+            println!(
+                "Refusing to send chunk {}, not a chunk of the file",
+                chunk_id
+            );
+            crate::send_message(stream, crate::get_bad_response()).ok();
+            return;
+        }
         let chunk = self.get_local_chunk(chunk_id);
+        let chunk_length = chunk.len() as u64;
         let response = crate::get_fetch_chunk_response(chunk);
-        crate::send_message(stream, response).ok();
+        if crate::send_message(stream, response).is_ok() {
+            self.progress
+                .uploaded
+                .fetch_add(chunk_length, Ordering::SeqCst);
+        }
     }
 }
 
-fn active_proof_loop(tracker_addr: SocketAddr, listening_addr: SocketAddr) {
+fn active_proof_loop(announcer: Announcer, mut announce_interval: Duration) {
+    let progress = &announcer.progress;
     loop {
-        let mut stream = TcpStream::connect(tracker_addr).expect("stream connect tracker error");
-        let request = crate::get_active_proof_request(listening_addr);
-        crate::send_message(&mut stream, request).unwrap();
-        std::thread::sleep(Duration::from_millis(2500));
+        std::thread::sleep(announce_interval);
+        let event = if progress.completed_event_pending.load(Ordering::SeqCst) {
+            Event::Completed
+        } else {
+            Event::None
+        };
+        let active_proof = announcer.query(event);
+        let intervals = announcer
+            .trackers
+            .each_tier(|tracker| announce::announce(tracker, &active_proof));
+        if event == Event::Completed && !intervals.is_empty() {
+            progress
+                .completed_event_pending
+                .store(false, Ordering::SeqCst);
+        }
+        announce_interval = next_announce_interval(intervals);
     }
 }
 
+/// Follow the tracker asking for the most frequent announces, so that no
+/// tracker lets us expire. Without an answer, retry soon.
+fn next_announce_interval(intervals: Vec<Duration>) -> Duration {
+    intervals
+        .into_iter()
+        .min()
+        .unwrap_or(DEFAULT_ANNOUNCE_INTERVAL)
+}
+
+/// Top the neighbor set up to `max_neighbors` from random samples of the
+/// swarm, taken from the subscriptions when there are some and asked of the
+/// trackers otherwise. Neighbors leave the set when they stop answering, or
+/// when a subscription tells they left.
 fn update_neighbors_loop(
-    tracker_addr: SocketAddr,
-    self_addr: SocketAddr,
-    neighbors: Arc<Mutex<HashMap<SocketAddr, Vec<ChunkId>>>>,
+    announcer: Announcer,
+    neighbors: Neighbors,
+    swarm_view: Arc<SwarmView>,
+    max_neighbors: usize,
 ) {
+    let trackers = &announcer.trackers;
+    let (listening_addr, info_hash, peer_id) = (
+        announcer.listening_addr,
+        announcer.info_hash,
+        announcer.peer_id,
+    );
     loop {
-        {
-            println!("Updating neighbors list");
-            let mut stream =
-                TcpStream::connect(tracker_addr).expect("stream connect tracker error");
-            let request = crate::get_peer_list_request();
-            crate::send_message(&mut stream, request).unwrap();
-            let peers = crate::read_peer_list_response(&mut stream).unwrap();
+        let wanted = max_neighbors.saturating_sub(neighbors.lock().unwrap().len());
+        if wanted > 0 && swarm_view.subscriptions.load(Ordering::SeqCst) > 0 {
+            let peers = swarm_view.peers.lock().unwrap();
             let mut neighbors = neighbors.lock().unwrap();
-            neighbors.retain(|neighbor, _| {
-                if !peers.contains(neighbor) {
-                    println!("Dropping {} because he no longer in peer list", neighbor);
+            let remote_fraction = *swarm_view.remote_fraction.lock().unwrap();
+            add_closest_neighbors(&peers, &mut neighbors, max_neighbors, remote_fraction);
+        } else if wanted > 0 {
+            println!("Updating neighbors list, looking for {} more", wanted);
+            let query = announcer.query(Event::None);
+            let peer_lists =
+                trackers.each_tier(|tracker| announce::peer_list(tracker, &query, wanted as u32));
+
+            let mut found = vec![];
+            for (neighbor_id, addr) in peer_lists.into_iter().flatten() {
+                let known = neighbors
+                    .lock()
+                    .unwrap()
+                    .values()
+                    .any(|neighbor| neighbor.addr == addr);
+                match neighbor_id {
+                    Some(neighbor_id) => found.push((neighbor_id, addr)),
+                    // UDP trackers give addresses only, the peer tells its id
+                    None if !known && addr != listening_addr => {
+                        match identify_neighbor(addr, info_hash, peer_id) {
+                            Ok(neighbor_id) => found.push((neighbor_id, addr)),
+                            Err(e) => println!("Cannot reach peer at {}: {}", addr, e),
+                        }
+                    }
+                    None => {}
                 }
-                peers.contains(neighbor)
-            });
-            for peer in peers {
-                if !neighbors.contains_key(&peer) && peer != self_addr {
-                    println!("Adding new neighbor: {}", peer);
-                    neighbors.insert(peer, vec![]);
+            }
+
+            let mut neighbors = neighbors.lock().unwrap();
+            for (neighbor_id, addr) in found {
+                if neighbors.len() >= max_neighbors {
+                    break;
+                }
+                if !neighbors.contains_key(&neighbor_id) && neighbor_id != peer_id {
+                    println!("Adding new neighbor: {} at {}", neighbor_id, addr);
+                    let chunks = vec![];
+                    neighbors.insert(neighbor_id, Neighbor { addr, chunks });
                 }
             }
         }
@@ -196,43 +513,146 @@ fn update_neighbors_loop(
     }
 }
 
-fn update_downloaded_chunks_loop(neighbors: Arc<Mutex<HashMap<SocketAddr, Vec<ChunkId>>>>) {
+/// Hold a subscription to one tracker of every tier, keeping `swarm_view` up
+/// to date with what they push, and subscribe again once they all dropped.
+fn subscription_loop(
+    trackers: TrackerTiers,
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    swarm_view: Arc<SwarmView>,
+    neighbors: Neighbors,
+    max_neighbors: usize,
+) {
+    loop {
+        let streams = trackers.each_tier(|tracker| {
+            announce::subscribe(tracker, info_hash, peer_id, trackers.passkey())
+        });
+        let followers: Vec<_> = streams
+            .into_iter()
+            .map(|stream| {
+                let swarm_view = Arc::clone(&swarm_view);
+                let neighbors = Arc::clone(&neighbors);
+                swarm_view.subscriptions.fetch_add(1, Ordering::SeqCst);
+                std::thread::spawn(move || {
+                    follow_subscription(stream, peer_id, &swarm_view, &neighbors, max_neighbors);
+                    swarm_view.subscriptions.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        if !followers.is_empty() {
+            println!("Subscribed to {} trackers", followers.len());
+        }
+        for follower in followers {
+            follower.join().ok();
+        }
+        println!("No subscription left, polling for peer lists");
+        std::thread::sleep(Duration::from_secs(10));
+    }
+}
+
+/// Apply the deltas pushed on a subscription until it drops. Peers that join
+/// fill free neighbor places at once, and neighbors that leave are dropped.
+fn follow_subscription(
+    mut stream: TcpStream,
+    peer_id: PeerId,
+    swarm_view: &SwarmView,
+    neighbors: &Mutex<HashMap<PeerId, Neighbor>>,
+    max_neighbors: usize,
+) {
+    while let Ok(changes) = crate::read_swarm_delta(&mut stream) {
+        let mut peers = swarm_view.peers.lock().unwrap();
+        let mut neighbors = neighbors.lock().unwrap();
+        *swarm_view.remote_fraction.lock().unwrap() = changes.remote_fraction;
+        for neighbor_id in changes.left {
+            peers.remove(&neighbor_id);
+            if let Some(neighbor) = neighbors.remove(&neighbor_id) {
+                println!("Dropping neighbor {} that left the swarm", neighbor.addr);
+            }
+        }
+        for (neighbor_id, addr, proximity) in changes.joined {
+            if neighbor_id != peer_id {
+                peers.insert(neighbor_id, (addr, proximity));
+            }
+        }
+        add_closest_neighbors(
+            &peers,
+            &mut neighbors,
+            max_neighbors,
+            changes.remote_fraction,
+        );
+    }
+}
+
+/// Fill the neighbors up to `max_neighbors` from the swarm view, the closest
+/// peers first.
+fn add_closest_neighbors(
+    peers: &HashMap<PeerId, (SocketAddr, Proximity)>,
+    neighbors: &mut HashMap<PeerId, Neighbor>,
+    max_neighbors: usize,
+    remote_fraction: f64,
+) {
+    let wanted = max_neighbors.saturating_sub(neighbors.len());
+    if wanted == 0 {
+        return;
+    }
+    let candidates = peers
+        .iter()
+        .filter(|(neighbor_id, _)| !neighbors.contains_key(neighbor_id))
+        .map(|(neighbor_id, (addr, proximity))| (*proximity, (*neighbor_id, *addr)))
+        .collect();
+    for (neighbor_id, addr) in locality::pick(candidates, wanted, remote_fraction) {
+        println!("Adding new neighbor: {} at {}", neighbor_id, addr);
+        let chunks = vec![];
+        neighbors.insert(neighbor_id, Neighbor { addr, chunks });
+    }
+}
+
+fn update_downloaded_chunks_loop(neighbors: Neighbors, torrent: Arc<Torrent>, peer_id: PeerId) {
+    let info_hash = torrent.info_hash;
     loop {
         {
             let neighbor_addrs = neighbors
                 .lock()
                 .unwrap()
-                .keys()
-                .cloned()
-                .collect::<Vec<SocketAddr>>();
-
-            for neighbor in neighbor_addrs {
-                println!("Getting downloaded chunks of neighbor: {}", neighbor);
-                let mut stream = if let Ok(s) = TcpStream::connect(neighbor) {
-                    s
-                } else {
-                    println!("Dropping neighbor: {}", neighbor);
-                    neighbors.lock().unwrap().remove(&neighbor);
-                    continue;
-                };
+                .iter()
+                .map(|(neighbor_id, neighbor)| (*neighbor_id, neighbor.addr))
+                .collect::<Vec<(PeerId, SocketAddr)>>();
+
+            for (neighbor_id, addr) in neighbor_addrs {
+                println!("Getting downloaded chunks of neighbor: {}", addr);
+                let mut stream =
+                    if let Ok(s) = connect_neighbor(neighbor_id, addr, info_hash, peer_id) {
+                        s
+                    } else {
+                        println!("Dropping neighbor: {}", addr);
+                        neighbors.lock().unwrap().remove(&neighbor_id);
+                        continue;
+                    };
 
                 let request = crate::get_chunks_query_request();
                 if crate::send_message(&mut stream, request).is_err() {
-                    println!("Dropping neighbor: {}", neighbor);
-                    neighbors.lock().unwrap().remove(&neighbor);
+                    println!("Dropping neighbor: {}", addr);
+                    neighbors.lock().unwrap().remove(&neighbor_id);
                     continue;
                 }
 
-                if let Ok(chunks) = crate::read_chunks_query_response(&mut stream) {
-                    println!("neighbor {} having {} chunks", neighbor, chunks.len());
-                    neighbors
-                        .lock()
-                        .unwrap()
-                        .entry(neighbor)
-                        .and_modify(|v| *v = chunks);
-                } else {
-                    println!("Dropping neighbor: {}", neighbor);
-                    neighbors.lock().unwrap().remove(&neighbor);
+                match crate::read_chunks_query_response(&mut stream) {
+                    Ok(chunks) if chunks.iter().all(|id| torrent.is_chunk_id(*id)) => {
+                        println!("neighbor {} having {} chunks", addr, chunks.len());
+                        neighbors
+                            .lock()
+                            .unwrap()
+                            .entry(neighbor_id)
+                            .and_modify(|neighbor| neighbor.chunks = chunks);
+                    }
+                    Ok(_) => {
+                        println!("Dropping neighbor: {}, having chunks not of the file", addr);
+                        neighbors.lock().unwrap().remove(&neighbor_id);
+                    }
+                    Err(_) => {
+                        println!("Dropping neighbor: {}", addr);
+                        neighbors.lock().unwrap().remove(&neighbor_id);
+                    }
                 }
             }
         }
@@ -241,48 +661,44 @@ fn update_downloaded_chunks_loop(neighbors: Arc<Mutex<HashMap<SocketAddr, Vec<Ch
 }
 
 // select a chunk I doesn't have, and fetch a random neighbor with that chunk
-fn fetch_chunk_loop(
-    neighbors: Arc<Mutex<HashMap<SocketAddr, Vec<ChunkId>>>>,
-    downloaded_chunks: Arc<Mutex<Vec<ChunkId>>>,
-    file: Arc<Mutex<File>>,
-    file_size: u64,
-) {
+fn fetch_chunk_loop(fetcher: ChunkFetcher) {
+    let ChunkFetcher {
+        neighbors,
+        downloaded_chunks,
+        progress,
+        ..
+    } = &fetcher;
     loop {
         let found;
-        {
+        if progress.refetch_pending() {
+            found = false;
+        } else {
             let target = {
                 use rand::seq::SliceRandom;
                 use rand::thread_rng;
                 let mut rng = thread_rng();
                 let neighbors_guard = neighbors.lock().unwrap();
-                let mut neighbor_addrs: Vec<SocketAddr> = neighbors_guard.keys().cloned().collect();
-                neighbor_addrs.shuffle(&mut rng);
+                let mut neighbor_ids: Vec<PeerId> = neighbors_guard.keys().cloned().collect();
+                neighbor_ids.shuffle(&mut rng);
                 let downloaded_chunks = downloaded_chunks.lock().unwrap();
-                let mut target: Option<(SocketAddr, ChunkId)> = None;
-                for neighbor_addr in neighbor_addrs {
-                    let chunk_id = neighbors_guard
-                        .get(&neighbor_addr)
-                        .unwrap()
+                let mut target: Option<(PeerId, SocketAddr, ChunkId)> = None;
+                for neighbor_id in neighbor_ids {
+                    let neighbor = neighbors_guard.get(&neighbor_id).unwrap();
+                    let chunk_id = neighbor
+                        .chunks
                         .iter()
                         .find(|chunk_id| !downloaded_chunks.contains(chunk_id));
                     if let Some(chunk_id) = chunk_id {
-                        target = Some((neighbor_addr, *chunk_id));
+                        target = Some((neighbor_id, neighbor.addr, *chunk_id));
                         break;
                     }
                 }
                 target
             };
 
-            if let Some((neighbor, chunk_id)) = target {
+            if let Some((neighbor_id, addr, chunk_id)) = target {
                 found = true;
-                fetch_chunk_from_neighbor(
-                    neighbor,
-                    chunk_id,
-                    Arc::clone(&neighbors),
-                    Arc::clone(&downloaded_chunks),
-                    Arc::clone(&file),
-                    file_size,
-                );
+                fetch_chunk_from_neighbor(&fetcher, neighbor_id, addr, chunk_id);
             } else {
                 found = false;
             }
@@ -295,54 +711,202 @@ fn fetch_chunk_loop(
 }
 
 fn fetch_chunk_from_neighbor(
+    fetcher: &ChunkFetcher,
+    neighbor_id: PeerId,
     neighbor: SocketAddr,
     chunk_id: ChunkId,
-    neighbors: Arc<Mutex<HashMap<SocketAddr, Vec<ChunkId>>>>,
-    downloaded_chunks: Arc<Mutex<Vec<ChunkId>>>,
-    file: Arc<Mutex<File>>,
-    file_size: u64,
 ) {
+    let ChunkFetcher {
+        neighbors,
+        downloaded_chunks,
+        storage,
+        torrent,
+        peer_id,
+        progress,
+        metrics,
+    } = fetcher;
     println!(
         "Attempt to fetch chunk {} from neighbor {}",
         chunk_id, neighbor
     );
-    let mut stream = if let Ok(stream) = TcpStream::connect(neighbor) {
-        stream
-    } else {
-        println!("Dropping neighbor: {}", neighbor);
-        neighbors.lock().unwrap().remove(&neighbor);
-        return;
-    };
+    let mut stream =
+        if let Ok(stream) = connect_neighbor(neighbor_id, neighbor, torrent.info_hash, *peer_id) {
+            stream
+        } else {
+            println!("Dropping neighbor: {}", neighbor);
+            metrics.failed_fetches.fetch_add(1, Ordering::SeqCst);
+            neighbors.lock().unwrap().remove(&neighbor_id);
+            return;
+        };
 
     let request = crate::get_fetch_chunk_request(chunk_id);
     if crate::send_message(&mut stream, request).is_err() {
         println!("Dropping neighbor: {}", neighbor);
-        neighbors.lock().unwrap().remove(&neighbor);
+        metrics.failed_fetches.fetch_add(1, Ordering::SeqCst);
+        neighbors.lock().unwrap().remove(&neighbor_id);
         return;
     }
     if let Ok(chunk) = crate::read_fetch_chunk_response(&mut stream) {
-        let mut downloaded_chunks = downloaded_chunks.lock().unwrap();
+        progress
+            .downloaded
+            .fetch_add(chunk.len() as u64, Ordering::SeqCst);
+        if !verify_chunk(chunk_id, &chunk, torrent) {
+            println!(
+                "Chunk {} from neighbor {} failed verification, dropping it",
+                chunk_id, neighbor
+            );
+            metrics.verification_failures.fetch_add(1, Ordering::SeqCst);
+            neighbors.lock().unwrap().remove(&neighbor_id);
+            return;
+        }
+        metrics.chunks_fetched.fetch_add(1, Ordering::SeqCst);
+
+        let mut downloaded = downloaded_chunks.lock().unwrap();
         // avoid duplicates chunk due to multithreading
-        if !downloaded_chunks.contains(&chunk_id) {
-            downloaded_chunks.push(chunk_id);
-            write_chunk_to_local(chunk_id, chunk, file, file_size);
+        if !downloaded.contains(&chunk_id) {
+            downloaded.push(chunk_id);
+            progress
+                .left
+                .fetch_sub(chunk.len() as u64, Ordering::SeqCst);
+            write_chunk_to_local(chunk_id, chunk, Arc::clone(storage));
+            // every other chunk was written while holding the lock, so the
+            // content is whole once the last one is in
+            if downloaded.len() as u64 == torrent.chunk_count() {
+                drop(downloaded);
+                if !on_download_complete(&storage.lock().unwrap(), torrent, progress) {
+                    // any neighbor may have sent the bad chunk
+                    downloaded_chunks.lock().unwrap().clear();
+                    neighbors.lock().unwrap().clear();
+                }
+            }
         }
     } else {
         println!("Dropping neighbor: {}", neighbor);
-        neighbors.lock().unwrap().remove(&neighbor);
+        metrics.failed_fetches.fetch_add(1, Ordering::SeqCst);
+        neighbors.lock().unwrap().remove(&neighbor_id);
+    }
+}
+
+/// Connect to a neighbor, making sure the peer answering at its address is
+/// still the one the trackers listed there.
+fn connect_neighbor(
+    neighbor_id: PeerId,
+    neighbor: SocketAddr,
+    info_hash: InfoHash,
+    peer_id: PeerId,
+) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(neighbor)?;
+    if crate::handshake(&mut stream, info_hash, peer_id)? != neighbor_id {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "another peer answered",
+        ));
+    }
+    Ok(stream)
+}
+
+/// Learn the id of the peer at `addr` by handshaking with it.
+fn identify_neighbor(addr: SocketAddr, info_hash: InfoHash, peer_id: PeerId) -> io::Result<PeerId> {
+    let mut stream = TcpStream::connect_timeout(&addr, NEIGHBOR_TIMEOUT)?;
+    stream.set_read_timeout(Some(NEIGHBOR_TIMEOUT))?;
+    crate::handshake(&mut stream, info_hash, peer_id)
+}
+
+fn render_metrics(
+    progress: &Progress,
+    neighbors: &Mutex<HashMap<PeerId, Neighbor>>,
+    metrics: &PeerMetrics,
+) -> Exposition {
+    let mut exposition = Exposition::default();
+    let transfer = progress.transfer();
+    exposition.counter(
+        "p2p_peer_uploaded_bytes_total",
+        "Bytes sent to neighbors.",
+        transfer.uploaded,
+    );
+    exposition.counter(
+        "p2p_peer_downloaded_bytes_total",
+        "Bytes received from neighbors, verified or not.",
+        transfer.downloaded,
+    );
+    exposition.gauge("p2p_peer_left_bytes", "Bytes still missing.", transfer.left);
+    exposition.counter(
+        "p2p_peer_chunks_fetched_total",
+        "Chunks fetched and verified.",
+        metrics.chunks_fetched.load(Ordering::SeqCst),
+    );
+    exposition.counter(
+        "p2p_peer_failed_fetches_total",
+        "Chunk fetches that failed on the way.",
+        metrics.failed_fetches.load(Ordering::SeqCst),
+    );
+    exposition.counter(
+        "p2p_peer_verification_failures_total",
+        "Chunks that did not match their piece hash.",
+        metrics.verification_failures.load(Ordering::SeqCst),
+    );
+    exposition.gauge(
+        "p2p_peer_neighbors",
+        "Neighbors currently exchanged with.",
+        neighbors.lock().unwrap().len() as u64,
+    );
+    exposition
+}
+
+fn chunk_length(chunk_id: ChunkId, torrent: &Torrent) -> u64 {
+    std::cmp::min(torrent.piece_length, torrent.file_size - chunk_id)
+}
+
+/// Check a fetched chunk against its piece hash, when the torrent has one.
+fn verify_chunk(chunk_id: ChunkId, chunk: &[u8], torrent: &Torrent) -> bool {
+    use sha1::{Digest, Sha1};
+
+    if chunk.len() as u64 != chunk_length(chunk_id, torrent) {
+        return false;
+    }
+    match torrent.piece_hash(chunk_id) {
+        Some(piece_hash) => Sha1::digest(chunk).as_slice() == piece_hash,
+        None => true,
+    }
+}
+
+/// Report the download complete once the whole content checks out. When it
+/// does not, some neighbor sent a bad chunk the piece hashes, if any, did
+/// not catch: the caller forgets every chunk, so that they are neither
+/// served nor counted as a seeder, and they are fetched again after a while.
+fn on_download_complete(storage: &Storage, torrent: &Torrent, progress: &Progress) -> bool {
+    if verify_download(storage, torrent) {
+        println!("Download complete");
+        progress
+            .completed_event_pending
+            .store(true, Ordering::SeqCst);
+        return true;
+    }
+    println!(
+        "Download complete, but the content does not match the torrent SHA-256, fetching it again in {:?}",
+        REFETCH_DELAY
+    );
+    progress.left.store(torrent.file_size, Ordering::SeqCst);
+    *progress.refetch_after.lock().unwrap() = Some(Instant::now() + REFETCH_DELAY);
+    false
+}
+
+/// Check the whole content against the torrent SHA-256, when it has one.
+fn verify_download(storage: &Storage, torrent: &Torrent) -> bool {
+    use sha2::{Digest, Sha256};
+
+    let expected = match &torrent.sha256 {
+        Some(sha256) => sha256,
+        None => return true,
+    };
+    let mut hasher = Sha256::new();
+    for chunk_id in torrent.chunk_ids() {
+        hasher.update(storage.read(chunk_id, chunk_length(chunk_id, torrent)));
     }
+    hasher.finalize().as_slice() == expected
 }
 
-fn write_chunk_to_local(chunk_id: ChunkId, chunk: Vec<u8>, file: Arc<Mutex<File>>, file_size: u64) {
+fn write_chunk_to_local(chunk_id: ChunkId, chunk: Vec<u8>, storage: Arc<Mutex<Storage>>) {
     println!("Writing chunk {} to local file system", chunk_id);
-    use crate::CHUNK_SIZE;
-    use std::cmp::min;
-
-    let start_position = chunk_id as usize;
-    let end_position = min(start_position + CHUNK_SIZE as usize, file_size as usize);
-    let file = file.lock().unwrap();
-    let mut map = unsafe { memmap::MmapMut::map_mut(&file).unwrap() };
-    (&mut map[start_position..end_position])
-        .write(&chunk[..])
-        .unwrap();
+    storage.lock().unwrap().write(chunk_id, &chunk);
 }
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+use crate::bencode;
+
+const PUBLISHER_KEY: &[u8] = b"publisher";
+const SIGNATURE_KEY: &[u8] = b"signature";
+
+/// Which publishers a peer trusts, and whether it accepts unsigned torrents.
+#[derive(Default)]
+pub struct SignaturePolicy {
+    pub trusted_keys: Vec<VerifyingKey>,
+    /// Refuse torrents that are unsigned or not signed by a trusted key.
+    pub strict: bool,
+}
+
+#[derive(Debug)]
+pub enum SignatureError {
+    Unsigned,
+    Malformed,
+    UntrustedPublisher,
+    BadSignature,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            SignatureError::Unsigned => "torrent is not signed",
+            SignatureError::Malformed => "torrent signature is malformed",
+            SignatureError::UntrustedPublisher => "torrent publisher is not trusted",
+            SignatureError::BadSignature => "torrent signature does not match its content",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+pub fn parse_verifying_key(hex: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(hex).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+pub fn parse_signing_key(hex: &str) -> Option<SigningKey> {
+    let bytes: [u8; 32] = hex::decode(hex.trim()).ok()?.try_into().ok()?;
+    Some(SigningKey::from_bytes(&bytes))
+}
+
+/// Check the publisher signature of a torrent document against `policy`.
+///
+/// Outside strict mode an unsigned or badly signed torrent is only reported.
+pub fn check(document: &bencode::Value, policy: &SignaturePolicy) -> Result<(), SignatureError> {
+    apply(verify(document, &policy.trusted_keys), policy)
+}
+
+/// Same as `check`, for a JSON torrent. It is only viewed as bencode when it
+/// carries a signature, so that an unsigned torrent holding values bencode
+/// has no equivalent for, such as floats, goes through the policy as unsigned.
+pub fn check_json(
+    document: &serde_json::Value,
+    policy: &SignaturePolicy,
+) -> Result<(), SignatureError> {
+    let verified =
+        json_document(document).and_then(|document| verify(&document, &policy.trusted_keys));
+    apply(verified, policy)
+}
+
+/// The publisher whose signature a torrent file carries, JSON or bencoded,
+/// once the signature is checked against it, whether it is trusted or not.
+pub fn signer(content: &[u8]) -> Result<VerifyingKey, SignatureError> {
+    let document = if content.first() == Some(&b'd') {
+        bencode::decode(content).map_err(|_| SignatureError::Malformed)?
+    } else {
+        let json = serde_json::from_slice(content).map_err(|_| SignatureError::Malformed)?;
+        json_document(&json)?
+    };
+    verify_signature(&document)
+}
+
+/// View a signed JSON torrent as bencode, to check its signature.
+fn json_document(document: &serde_json::Value) -> Result<bencode::Value, SignatureError> {
+    if document.get("publisher").is_none() && document.get("signature").is_none() {
+        return Err(SignatureError::Unsigned);
+    }
+    json_to_bencode(document).ok_or(SignatureError::Malformed)
+}
+
+fn apply(
+    verified: Result<(), SignatureError>,
+    policy: &SignaturePolicy,
+) -> Result<(), SignatureError> {
+    match verified {
+        Ok(()) => Ok(()),
+        Err(e) if policy.strict => Err(e),
+        Err(e) => {
+            println!("Warning: {}", e);
+            Ok(())
+        }
+    }
+}
+
+fn verify(document: &bencode::Value, trusted_keys: &[VerifyingKey]) -> Result<(), SignatureError> {
+    let publisher = verify_signature(document)?;
+    if !trusted_keys.contains(&publisher) {
+        return Err(SignatureError::UntrustedPublisher);
+    }
+    Ok(())
+}
+
+/// Check a document against the publisher key it records, returning that key.
+fn verify_signature(document: &bencode::Value) -> Result<VerifyingKey, SignatureError> {
+    let fields = document.as_dict().ok_or(SignatureError::Malformed)?;
+    let (publisher, signature) = match (fields.get(PUBLISHER_KEY), fields.get(SIGNATURE_KEY)) {
+        (Some(publisher), Some(signature)) => (publisher, signature),
+        (None, None) => return Err(SignatureError::Unsigned),
+        _ => return Err(SignatureError::Malformed),
+    };
+    let publisher = publisher
+        .as_str()
+        .and_then(parse_verifying_key)
+        .ok_or(SignatureError::Malformed)?;
+    let signature: [u8; 64] = signature
+        .as_str()
+        .and_then(|hex| hex::decode(hex).ok())
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(SignatureError::Malformed)?;
+    publisher
+        .verify(&signed_bytes(fields), &Signature::from_bytes(&signature))
+        .map_err(|_| SignatureError::BadSignature)?;
+    Ok(publisher)
+}
+
+/// Sign a torrent document in place, recording the publisher key next to the
+/// signature.
+pub fn sign(document: &mut bencode::Value, signing_key: &SigningKey) {
+    if let bencode::Value::Dict(fields) = document {
+        let publisher = hex::encode(signing_key.verifying_key().as_bytes());
+        fields.insert(
+            PUBLISHER_KEY.to_vec(),
+            bencode::Value::Bytes(publisher.into_bytes()),
+        );
+        let signature = hex::encode(signing_key.sign(&signed_bytes(fields)).to_bytes());
+        fields.insert(
+            SIGNATURE_KEY.to_vec(),
+            bencode::Value::Bytes(signature.into_bytes()),
+        );
+    }
+}
+
+/// Sign a torrent file, either JSON or bencoded, rewriting it in place.
+pub fn sign_file(path: &Path, signing_key: &SigningKey) -> io::Result<()> {
+    let invalid = |reason| io::Error::new(io::ErrorKind::InvalidData, reason);
+    let content = std::fs::read(path)?;
+    if content.first() == Some(&b'd') {
+        let mut document = bencode::decode(&content).map_err(|_| invalid("bad torrent file"))?;
+        sign(&mut document, signing_key);
+        return std::fs::write(path, bencode::encode(&document));
+    }
+
+    let mut json: serde_json::Value =
+        serde_json::from_slice(&content).map_err(|_| invalid("bad torrent file"))?;
+    let fields = json
+        .as_object_mut()
+        .ok_or_else(|| invalid("bad torrent file"))?;
+    fields.remove("signature");
+    let mut document = json_to_bencode(&serde_json::Value::Object(fields.clone()))
+        .ok_or_else(|| invalid("torrent holds values that cannot be signed"))?;
+    sign(&mut document, signing_key);
+    for key in &["publisher", "signature"] {
+        let value = document.get(key).and_then(bencode::Value::as_str).unwrap();
+        fields.insert(key.to_string(), serde_json::Value::from(value));
+    }
+    std::fs::write(path, serde_json::to_string_pretty(&json)?)
+}
+
+/// The signature covers the whole document, the publisher key included, but
+/// not the signature itself.
+fn signed_bytes(fields: &BTreeMap<Vec<u8>, bencode::Value>) -> Vec<u8> {
+    let mut fields = fields.clone();
+    fields.remove(SIGNATURE_KEY);
+    bencode::encode(&bencode::Value::Dict(fields))
+}
+
+/// View a JSON torrent as a bencode document so both formats are signed alike.
+pub fn json_to_bencode(value: &serde_json::Value) -> Option<bencode::Value> {
+    use serde_json::Value;
+    Some(match value {
+        Value::Number(number) => bencode::Value::Integer(number.as_i64()?),
+        Value::String(string) => bencode::Value::Bytes(string.clone().into_bytes()),
+        Value::Array(array) => {
+            bencode::Value::List(array.iter().map(json_to_bencode).collect::<Option<_>>()?)
+        }
+        Value::Object(object) => bencode::Value::Dict(
+            object
+                .iter()
+                .map(|(key, value)| Some((key.clone().into_bytes(), json_to_bencode(value)?)))
+                .collect::<Option<_>>()?,
+        ),
+        Value::Bool(_) | Value::Null => return None,
+    })
+}
@@ -0,0 +1,116 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::torrent::Torrent;
+
+/// The bytes of a torrent laid out over one or more files on disk.
+///
+/// Offsets are positions in the concatenation of all files, the same
+/// addressing chunk ids use.
+pub struct Storage {
+    files: Vec<StorageFile>,
+}
+
+struct StorageFile {
+    file: File,
+    offset: u64,
+    length: u64,
+}
+
+impl Storage {
+    /// Create empty files for a download, at `path` itself for a single file
+    /// torrent and under the directory `path` for a multi-file torrent.
+    pub fn create(path: &Path, torrent: &Torrent) -> Self {
+        let files = layout(path, torrent)
+            .into_iter()
+            .map(|(file_path, offset, length)| {
+                if let Some(parent) = file_path.parent() {
+                    std::fs::create_dir_all(parent).expect("create directory error");
+                }
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .truncate(true)
+                    .create(true)
+                    .open(&file_path)
+                    .expect("open file error");
+                file.set_len(length).unwrap();
+                StorageFile {
+                    file,
+                    offset,
+                    length,
+                }
+            })
+            .collect();
+        Storage { files }
+    }
+
+    /// Open the existing files of a seeder for reading.
+    pub fn open(path: &Path, torrent: &Torrent) -> Self {
+        let files = layout(path, torrent)
+            .into_iter()
+            .map(|(file_path, offset, length)| StorageFile {
+                file: OpenOptions::new()
+                    .read(true)
+                    .open(&file_path)
+                    .expect("open file error"),
+                offset,
+                length,
+            })
+            .collect();
+        Storage { files }
+    }
+
+    pub fn read(&self, offset: u64, length: u64) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(length as usize);
+        for (file, start, end) in self.spans(offset, length) {
+            let map = unsafe { memmap::Mmap::map(&file.file).unwrap() };
+            bytes.extend_from_slice(&map[start..end]);
+        }
+        bytes
+    }
+
+    pub fn write(&self, offset: u64, bytes: &[u8]) {
+        let mut written = 0;
+        for (file, start, end) in self.spans(offset, bytes.len() as u64) {
+            let mut map = unsafe { memmap::MmapMut::map_mut(&file.file).unwrap() };
+            (&mut map[start..end])
+                .write_all(&bytes[written..written + end - start])
+                .unwrap();
+            written += end - start;
+        }
+    }
+
+    /// The files touched by a byte range, with the range local to each file.
+    fn spans(&self, offset: u64, length: u64) -> Vec<(&StorageFile, usize, usize)> {
+        let end = offset + length;
+        self.files
+            .iter()
+            .filter(|file| file.length > 0)
+            .filter(|file| file.offset < end && offset < file.offset + file.length)
+            .map(|file| {
+                let start = offset.max(file.offset) - file.offset;
+                let stop = end.min(file.offset + file.length) - file.offset;
+                (file, start as usize, stop as usize)
+            })
+            .collect()
+    }
+}
+
+fn layout(path: &Path, torrent: &Torrent) -> Vec<(PathBuf, u64, u64)> {
+    if torrent.files.is_empty() {
+        return vec![(path.to_path_buf(), 0, torrent.file_size)];
+    }
+
+    let mut offset = 0;
+    torrent
+        .files
+        .iter()
+        .map(|file| {
+            let entry = (path.join(&file.path), offset, file.length);
+            offset += file.length;
+            entry
+        })
+        .collect()
+}
@@ -1,30 +1,587 @@
-use std::net::SocketAddr;
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::path::{Component, Path, PathBuf};
 
 use serde_json::Value;
+use sha1::{Digest, Sha1};
+
+use crate::announce::TrackerAddr;
+use crate::bencode;
+use crate::signing::{self, SignatureError, SignaturePolicy};
+
+/// SHA-1 of the bencoded `info` dictionary, identifying the content of a swarm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InfoHash(pub [u8; 20]);
+
+impl InfoHash {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut info_hash = [0; 20];
+        if bytes.len() != info_hash.len() {
+            return None;
+        }
+        info_hash.copy_from_slice(bytes);
+        Some(InfoHash(info_hash))
+    }
+
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        InfoHash::from_bytes(&hex::decode(hex).ok()?)
+    }
+
+    fn of_info(info: &bencode::Value) -> Self {
+        InfoHash::of_encoded_info(&bencode::encode(info))
+    }
+
+    fn of_encoded_info(info: &[u8]) -> Self {
+        InfoHash(Sha1::digest(info).into())
+    }
+}
+
+impl fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+/// Why a torrent file cannot be used.
+#[derive(Debug)]
+pub enum TorrentError {
+    Io(io::Error),
+    /// The file is not a torrent we understand, for the given reason.
+    Malformed(String),
+    Signature(SignatureError),
+}
+
+impl fmt::Display for TorrentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TorrentError::Io(e) => write!(f, "cannot read torrent file: {}", e),
+            TorrentError::Malformed(reason) => write!(f, "bad torrent file: {}", reason),
+            TorrentError::Signature(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TorrentError {}
+
+impl From<SignatureError> for TorrentError {
+    fn from(e: SignatureError) -> Self {
+        TorrentError::Signature(e)
+    }
+}
+
+fn malformed(reason: &str) -> TorrentError {
+    TorrentError::Malformed(reason.to_string())
+}
 
 pub struct Torrent {
+    pub info_hash: InfoHash,
     pub file_size: u64,
-    pub tracker_addr: SocketAddr,
+    pub piece_length: u64,
+    /// SHA-1 of every piece, empty when the torrent carries none.
+    pub piece_hashes: Vec<[u8; 20]>,
+    /// Files of a multi-file torrent in order, empty for a single file.
+    pub files: Vec<TorrentFile>,
+    /// Trackers grouped into tiers. A peer announces to every tier and fails
+    /// over between the trackers of a tier.
+    pub trackers: Vec<Vec<TrackerAddr>>,
+    /// Suggested name of the downloaded file, or directory for multi-file torrents.
+    pub name: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub creation_date: Option<u64>,
+    pub created_by: Option<String>,
+    pub comment: Option<String>,
+    /// SHA-256 of the whole content, checked once a download completes.
+    pub sha256: Option<[u8; 32]>,
+}
+
+pub struct TorrentFile {
+    /// Path relative to the download directory.
+    pub path: PathBuf,
+    pub length: u64,
 }
 
 impl Torrent {
-    pub fn from_file(path: &Path) -> Self {
+    /// Load either our JSON torrent or a BitTorrent v1 metainfo file, checking
+    /// its publisher signature against `policy`.
+    pub fn from_file(path: &Path, policy: &SignaturePolicy) -> Result<Self, TorrentError> {
+        Torrent::from_bytes(&std::fs::read(path).map_err(TorrentError::Io)?, policy)
+    }
+
+    /// Same as `from_file`, for the content of a torrent file.
+    pub fn from_bytes(content: &[u8], policy: &SignaturePolicy) -> Result<Self, TorrentError> {
+        let torrent = Torrent::parse(content)?;
+        // parsing succeeded, so the document decodes
+        if content.first() == Some(&b'd') {
+            signing::check(&bencode::decode(content).unwrap(), policy)?;
+        } else {
+            signing::check_json(&serde_json::from_slice(content).unwrap(), policy)?;
+        }
+        Ok(torrent)
+    }
+
+    /// Either our JSON torrent or a BitTorrent v1 metainfo file, leaving its
+    /// signature unchecked.
+    pub fn parse(content: &[u8]) -> Result<Self, TorrentError> {
+        if content.first() == Some(&b'd') {
+            Torrent::from_bencode(content)
+        } else {
+            let content = std::str::from_utf8(content).map_err(|_| malformed("not UTF-8"))?;
+            Torrent::from_json(content)
+        }
+    }
+
+    pub fn from_json(content: &str) -> Result<Self, TorrentError> {
         let values: Value =
-            serde_json::from_str(std::fs::read_to_string(path).unwrap().as_str()).unwrap();
-        let values = values.as_object().unwrap();
-        let file_size = values.get("file_size").unwrap().as_u64().unwrap();
-        let tracker_addr = values
-            .get("tracker_addr")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .parse()
-            .unwrap();
-
-        Torrent {
+            serde_json::from_str(content).map_err(|e| TorrentError::Malformed(e.to_string()))?;
+        let values = values
+            .as_object()
+            .ok_or_else(|| malformed("not an object"))?;
+        let file_size = values
+            .get("file_size")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| malformed("no file_size"))?;
+        let piece_length = match values.get("piece_length") {
+            Some(value) => value
+                .as_u64()
+                .filter(|piece_length| *piece_length > 0)
+                .ok_or_else(|| malformed("bad piece_length"))?,
+            None => crate::CHUNK_SIZE,
+        };
+
+        // "trackers" is either a list of addresses, each one being its own tier,
+        // or a list of tiers. A lone "tracker_addr" is still accepted.
+        let mut trackers = vec![];
+        if let Some(addr) = values.get("tracker_addr") {
+            trackers.push(vec![parse_tracker_addr(addr)?]);
+        }
+        if let Some(entries) = values.get("trackers") {
+            let entries = entries
+                .as_array()
+                .ok_or_else(|| malformed("bad trackers"))?;
+            for entry in entries {
+                if let Some(tier) = entry.as_array() {
+                    trackers.push(
+                        tier.iter()
+                            .map(parse_tracker_addr)
+                            .collect::<Result<_, _>>()?,
+                    );
+                } else {
+                    trackers.push(vec![parse_tracker_addr(entry)?]);
+                }
+            }
+        }
+        trackers.retain(|tier| !tier.is_empty());
+        if trackers.is_empty() {
+            return Err(malformed("no tracker"));
+        }
+
+        let string_field = |key: &str| -> Result<Option<String>, TorrentError> {
+            match values.get(key) {
+                Some(value) => match value.as_str() {
+                    Some(value) => Ok(Some(value.to_string())),
+                    None => Err(TorrentError::Malformed(format!("bad {}", key))),
+                },
+                None => Ok(None),
+            }
+        };
+        let name = string_field("name")?;
+        let sha256: Option<[u8; 32]> = match string_field("sha256")? {
+            Some(hex) => Some(
+                hex::decode(hex)
+                    .ok()
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .ok_or_else(|| malformed("bad sha256"))?,
+            ),
+            None => None,
+        };
+        let creation_date = match values.get("creation_date") {
+            Some(value) => Some(
+                value
+                    .as_u64()
+                    .ok_or_else(|| malformed("bad creation_date"))?,
+            ),
+            None => None,
+        };
+
+        // the size, name and content hash, when present, identify the swarm,
+        // while the trackers and the other descriptive fields are left out so
+        // that they can change without changing it
+        let mut info = BTreeMap::new();
+        info.insert(
+            b"length".to_vec(),
+            bencode::Value::Integer(file_size as i64),
+        );
+        info.insert(
+            b"piece length".to_vec(),
+            bencode::Value::Integer(piece_length as i64),
+        );
+        if let Some(name) = &name {
+            info.insert(
+                b"name".to_vec(),
+                bencode::Value::Bytes(name.clone().into_bytes()),
+            );
+        }
+        if let Some(sha256) = &sha256 {
+            info.insert(b"sha256".to_vec(), bencode::Value::Bytes(sha256.to_vec()));
+        }
+
+        Ok(Torrent {
+            info_hash: InfoHash::of_info(&bencode::Value::Dict(info)),
+            file_size,
+            piece_length,
+            piece_hashes: vec![],
+            files: vec![],
+            trackers,
+            name,
+            creation_date,
+            created_by: string_field("created_by")?,
+            comment: string_field("comment")?,
+            sha256,
+        })
+    }
+
+    pub fn from_bencode(content: &[u8]) -> Result<Self, TorrentError> {
+        let metainfo =
+            bencode::decode(content).map_err(|e| TorrentError::Malformed(e.to_string()))?;
+        let info = metainfo
+            .get("info")
+            .ok_or_else(|| malformed("no info dictionary"))?;
+
+        let piece_length = info
+            .get("piece length")
+            .and_then(bencode::Value::as_integer)
+            .filter(|piece_length| *piece_length > 0)
+            .ok_or_else(|| malformed("bad piece length"))? as u64;
+        let pieces = info
+            .get("pieces")
+            .and_then(bencode::Value::as_bytes)
+            .ok_or_else(|| malformed("no pieces"))?;
+        if !pieces.len().is_multiple_of(20) {
+            return Err(malformed("pieces are not SHA-1 hashes"));
+        }
+        let piece_hashes: Vec<[u8; 20]> = pieces
+            .chunks(20)
+            .map(|hash| {
+                let mut piece_hash = [0; 20];
+                piece_hash.copy_from_slice(hash);
+                piece_hash
+            })
+            .collect();
+
+        let (file_size, files) = if let Some(length) = info.get("length") {
+            let length = length
+                .as_integer()
+                .filter(|length| *length >= 0)
+                .ok_or_else(|| malformed("bad length"))?;
+            (length as u64, vec![])
+        } else {
+            let files: Vec<TorrentFile> = info
+                .get("files")
+                .and_then(bencode::Value::as_list)
+                .ok_or_else(|| malformed("neither length nor files"))?
+                .iter()
+                .map(parse_torrent_file)
+                .collect::<Result<_, _>>()?;
+            (files.iter().map(|file| file.length).sum(), files)
+        };
+
+        // "announce-list" holds the tiers and supersedes "announce" when present
+        let announce_urls: Vec<Vec<&str>> = match metainfo
+            .get("announce-list")
+            .and_then(bencode::Value::as_list)
+        {
+            Some(tiers) => tiers
+                .iter()
+                .filter_map(bencode::Value::as_list)
+                .map(|tier| tier.iter().filter_map(bencode::Value::as_str).collect())
+                .collect(),
+            None => metainfo
+                .get("announce")
+                .and_then(bencode::Value::as_str)
+                .map(|url| vec![vec![url]])
+                .unwrap_or_default(),
+        };
+        let mut trackers: Vec<Vec<TrackerAddr>> = announce_urls
+            .into_iter()
+            .map(|tier| {
+                tier.into_iter()
+                    .filter_map(|url| {
+                        let addr = announce_url_to_addr(url);
+                        if addr.is_none() {
+                            println!("Skipping unresolvable tracker {}", url);
+                        }
+                        addr
+                    })
+                    .collect()
+            })
+            .collect();
+        trackers.retain(|tier: &Vec<TrackerAddr>| !tier.is_empty());
+        if trackers.is_empty() {
+            return Err(malformed("no tracker"));
+        }
+        if piece_hashes.len() as u64 != file_size.div_ceil(piece_length) {
+            return Err(malformed("piece count does not match the length"));
+        }
+
+        let string_field = |value: Option<&bencode::Value>| {
+            value.and_then(bencode::Value::as_str).map(str::to_string)
+        };
+
+        let sha256 = match info.get("sha256") {
+            Some(sha256) => Some(
+                sha256
+                    .as_bytes()
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .ok_or_else(|| malformed("bad sha256"))?,
+            ),
+            None => None,
+        };
+
+        let creation_date = match metainfo.get("creation date") {
+            Some(date) => Some(
+                date.as_integer()
+                    .filter(|date| *date >= 0)
+                    .ok_or_else(|| malformed("bad creation date"))? as u64,
+            ),
+            None => None,
+        };
+        let encoded_info = bencode::raw_dict_value(content, "info")
+            .map_err(|e| TorrentError::Malformed(e.to_string()))?
+            .ok_or_else(|| malformed("no info dictionary"))?;
+
+        Ok(Torrent {
+            info_hash: InfoHash::of_encoded_info(encoded_info),
             file_size,
-            tracker_addr,
+            piece_length,
+            piece_hashes,
+            files,
+            trackers,
+            name: string_field(info.get("name")),
+            creation_date,
+            created_by: string_field(metainfo.get("created by")),
+            comment: string_field(metainfo.get("comment")),
+            sha256,
+        })
+    }
+
+    /// The expected SHA-1 of the chunk starting at `chunk_id`, if known.
+    pub fn piece_hash(&self, chunk_id: u64) -> Option<&[u8; 20]> {
+        self.piece_hashes
+            .get((chunk_id / self.piece_length) as usize)
+    }
+
+    pub fn chunk_count(&self) -> u64 {
+        self.file_size.div_ceil(self.piece_length)
+    }
+
+    /// Where to save the download when the user gives no path: the suggested
+    /// name, stripped of any directory so it stays in the working directory.
+    pub fn default_output_path(&self) -> Option<PathBuf> {
+        let name = Path::new(self.name.as_ref()?).file_name()?;
+        Some(PathBuf::from(name))
+    }
+
+    pub fn chunk_ids(&self) -> Vec<u64> {
+        (0..self.file_size)
+            .step_by(self.piece_length as usize)
+            .collect()
+    }
+
+    /// Whether a chunk id, as given by another peer, starts a chunk of the file.
+    pub fn is_chunk_id(&self, chunk_id: u64) -> bool {
+        chunk_id < self.file_size && chunk_id.is_multiple_of(self.piece_length)
+    }
+}
+
+fn parse_tracker_addr(value: &Value) -> Result<TrackerAddr, TorrentError> {
+    value
+        .as_str()
+        .and_then(TrackerAddr::parse)
+        .ok_or_else(|| TorrentError::Malformed(format!("bad tracker address {}", value)))
+}
+
+fn parse_torrent_file(file: &bencode::Value) -> Result<TorrentFile, TorrentError> {
+    let length = file
+        .get("length")
+        .and_then(bencode::Value::as_integer)
+        .filter(|length| *length >= 0)
+        .ok_or_else(|| malformed("file with no length"))? as u64;
+    let path: PathBuf = file
+        .get("path")
+        .and_then(bencode::Value::as_list)
+        .ok_or_else(|| malformed("file with no path"))?
+        .iter()
+        .map(|component| component.as_str().ok_or_else(|| malformed("bad file path")))
+        .collect::<Result<_, _>>()?;
+    // a path escaping the download directory would let a torrent overwrite any file
+    if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(TorrentError::Malformed(format!(
+            "unsafe file path {}",
+            path.display()
+        )));
+    }
+    Ok(TorrentFile { path, length })
+}
+
+/// Map an announce URL such as `http://tracker.example:6969/announce` to the
+/// address of the tracker behind it. `udp://` URLs name UDP trackers, any
+/// other scheme one speaking our own protocol.
+fn announce_url_to_addr(url: &str) -> Option<TrackerAddr> {
+    let (scheme, rest) = match url.find("://") {
+        Some(index) => (&url[..index], &url[index + 3..]),
+        None => ("", url),
+    };
+    let authority = rest.split('/').next()?;
+    if scheme == "udp" {
+        return TrackerAddr::parse(url);
+    }
+    if let Some(addr) = authority.to_socket_addrs().ok().and_then(|mut a| a.next()) {
+        return Some(TrackerAddr::Tcp(addr));
+    }
+    let default_port = match scheme {
+        "http" => 80,
+        "https" => 443,
+        _ => return None,
+    };
+    (authority, default_port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .map(TrackerAddr::Tcp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A torrent announcing to `announce`, with `info` as its info dictionary.
+    fn metainfo(announce: &str, info: &[u8]) -> Vec<u8> {
+        let mut content = format!("d8:announce{}:{}4:info", announce.len(), announce).into_bytes();
+        content.extend(info);
+        content.push(b'e');
+        content
+    }
+
+    /// The info dictionary of a file holding "hello", its keys sorted or not.
+    fn hello_info(keys_sorted: bool, piece_length: u64) -> Vec<u8> {
+        let (first, second) = ("6:lengthi5e", "4:name5:hello");
+        let mut info = if keys_sorted {
+            format!("d{}{}", first, second)
+        } else {
+            format!("d{}{}", second, first)
         }
+        .into_bytes();
+        info.extend(format!("12:piece lengthi{}e6:pieces20:", piece_length).as_bytes());
+        info.extend(hex::decode("aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d").unwrap());
+        info.push(b'e');
+        info
+    }
+
+    #[test]
+    fn info_hash_of_a_known_torrent() {
+        let content = metainfo("udp://127.0.0.1:6969/announce", &hello_info(true, 16384));
+        let torrent = Torrent::from_bencode(&content).unwrap();
+        assert_eq!(
+            torrent.info_hash.to_string(),
+            "bdb46b45d540f614aa67b474ea1e59a0857fb38f"
+        );
+        assert_eq!(torrent.file_size, 5);
+        assert_eq!(torrent.chunk_count(), 1);
+        assert_eq!(torrent.name.as_deref(), Some("hello"));
+        assert_eq!(
+            torrent.trackers,
+            vec![vec![TrackerAddr::Udp("127.0.0.1:6969".parse().unwrap())]]
+        );
+    }
+
+    #[test]
+    fn info_hash_of_unsorted_keys_hashes_the_raw_bytes() {
+        let info = hello_info(false, 16384);
+        let torrent = Torrent::from_bencode(&metainfo("127.0.0.1:8000", &info)).unwrap();
+        assert_eq!(
+            torrent.info_hash.to_string(),
+            "db5a67e3adf67a5c6668a073707b821a45d6bda7"
+        );
+        assert_ne!(
+            torrent.info_hash,
+            InfoHash::of_info(&bencode::decode(&info).unwrap())
+        );
+    }
+
+    #[test]
+    fn refuses_a_zero_piece_length() {
+        let content = metainfo("127.0.0.1:8000", &hello_info(true, 0));
+        match Torrent::from_bencode(&content) {
+            Err(TorrentError::Malformed(reason)) => assert_eq!(reason, "bad piece length"),
+            _ => panic!("zero piece length accepted"),
+        }
+    }
+
+    #[test]
+    fn json_torrents_without_a_content_hash() {
+        let bare =
+            Torrent::from_json(r#"{"file_size": 5, "tracker_addr": "127.0.0.1:8000"}"#).unwrap();
+        assert_eq!(bare.sha256, None);
+        assert_eq!(bare.file_size, 5);
+
+        let hashed = Torrent::from_json(&format!(
+            r#"{{"file_size": 5, "tracker_addr": "127.0.0.1:8000", "sha256": "{}"}}"#,
+            "00".repeat(32)
+        ))
+        .unwrap();
+        assert_eq!(hashed.sha256, Some([0; 32]));
+        assert_ne!(bare.info_hash, hashed.info_hash);
+    }
+
+    #[test]
+    fn chunk_ids_start_pieces_within_the_file() {
+        let torrent = Torrent::from_json(
+            r#"{"file_size": 10, "piece_length": 4, "tracker_addr": "127.0.0.1:8000"}"#,
+        )
+        .unwrap();
+        assert_eq!(torrent.chunk_ids(), vec![0, 4, 8]);
+        assert!(torrent
+            .chunk_ids()
+            .into_iter()
+            .all(|id| torrent.is_chunk_id(id)));
+        assert!(!torrent.is_chunk_id(2));
+        assert!(!torrent.is_chunk_id(12));
+        assert!(!torrent.is_chunk_id(u64::MAX));
+    }
+
+    #[test]
+    fn info_hash_from_hex() {
+        let hex = "bdb46b45d540f614aa67b474ea1e59a0857fb38f";
+        assert_eq!(InfoHash::from_hex(hex).unwrap().to_string(), hex);
+        assert_eq!(InfoHash::from_hex(&hex[2..]), None);
+        assert_eq!(InfoHash::from_hex("not hex"), None);
+    }
+
+    #[test]
+    fn announce_urls() {
+        let tcp = |addr: &str| Some(TrackerAddr::Tcp(addr.parse().unwrap()));
+        assert_eq!(
+            announce_url_to_addr("udp://127.0.0.1:6969/announce"),
+            Some(TrackerAddr::Udp("127.0.0.1:6969".parse().unwrap()))
+        );
+        assert_eq!(
+            announce_url_to_addr("http://127.0.0.1:6969/announce"),
+            tcp("127.0.0.1:6969")
+        );
+        assert_eq!(
+            announce_url_to_addr("http://127.0.0.1/announce"),
+            tcp("127.0.0.1:80")
+        );
+        assert_eq!(
+            announce_url_to_addr("https://127.0.0.1"),
+            tcp("127.0.0.1:443")
+        );
+        assert_eq!(announce_url_to_addr("[::1]:8000"), tcp("[::1]:8000"));
+        assert_eq!(announce_url_to_addr("wss://127.0.0.1/announce"), None);
+        assert_eq!(announce_url_to_addr("udp://127.0.0.1/announce"), None);
     }
 }
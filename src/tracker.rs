@@ -1,138 +1,1394 @@
-use std::collections::HashMap;
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde_json::{json, Value};
 
 use threadpool::ThreadPool;
 
+use crate::announce::{Announce, SUBSCRIPTION_KEEPALIVE};
+use crate::locality::{self, Cidr, Locality, Proximity};
+use crate::metrics::{Exposition, LabeledCounter};
+use crate::peer::PeerId;
+use crate::requests::request::{Event, Transfer};
 use crate::responses;
+use crate::responses::response::SwarmStats;
+use crate::torrent::InfoHash;
+
+mod access;
+mod admin;
+mod deployment;
+mod events;
+mod federation;
+mod http;
+mod limits;
+mod registry;
+mod udp;
+
+/// A listed peer, as of its last announce.
+struct SwarmPeer {
+    addr: SocketAddr,
+    last_seen: SystemTime,
+    /// Peers with nothing left are seeders, the others leechers.
+    transfer: Transfer,
+}
+
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<PeerId, SwarmPeer>,
+    /// Downloads reported complete since the tracker started.
+    completed: u64,
+    /// Peers being connected back to before they are listed.
+    unverified: HashSet<PeerId>,
+    /// Peers that left, and when, so that the gossip of federated trackers
+    /// does not list them again.
+    departed: HashMap<PeerId, SystemTime>,
+    /// Peers evicted by an admin, and when, refused until they would have
+    /// expired.
+    evicted: HashMap<PeerId, SystemTime>,
+}
+
+impl Swarm {
+    /// List a peer, or refresh its entry. A peer listed at the same address
+    /// under another id is gone, most likely restarted, and is dropped.
+    fn insert(&mut self, peer_id: PeerId, addr: SocketAddr, transfer: Transfer) {
+        self.insert_seen(peer_id, addr, transfer, SystemTime::now());
+    }
+
+    fn insert_seen(
+        &mut self,
+        peer_id: PeerId,
+        addr: SocketAddr,
+        transfer: Transfer,
+        last_seen: SystemTime,
+    ) {
+        self.peers
+            .retain(|other_id, other| *other_id == peer_id || other.addr != addr);
+        self.departed.remove(&peer_id);
+        self.peers.insert(
+            peer_id,
+            SwarmPeer {
+                addr,
+                last_seen,
+                transfer,
+            },
+        );
+    }
+
+    /// Where a peer is: at its listed address if it is listed, as with
+    /// `trust_client_addr` it need not be where its requests come from.
+    fn ip_of(&self, peer_id: Option<PeerId>, source: IpAddr) -> IpAddr {
+        peer_id
+            .and_then(|peer_id| self.peers.get(&peer_id))
+            .map_or(source, |peer| peer.addr.ip())
+    }
+
+    /// Drop a peer that left, remembering when.
+    fn depart(&mut self, peer_id: PeerId) -> Option<SwarmPeer> {
+        self.unverified.remove(&peer_id);
+        self.departed.insert(peer_id, SystemTime::now());
+        self.peers.remove(&peer_id)
+    }
+}
+
+type Swarms = HashMap<InfoHash, Swarm>;
+
+/// A connection kept open to push the changes of a swarm to a peer.
+struct Subscription {
+    peer_id: PeerId,
+    /// Where the subscriber is, to tell how close the others are.
+    ip: IpAddr,
+    stream: TcpStream,
+    /// Counts the stream against the subscriptions of its source until it is
+    /// closed.
+    _connection: limits::Connection,
+}
+
+type Subscriptions = HashMap<InfoHash, Vec<Subscription>>;
+
+#[derive(Default)]
+struct TrackerMetrics {
+    /// Requests received, by type.
+    requests: LabeledCounter,
+    /// Peers dropped for not announcing in time.
+    expirations: AtomicU64,
+    /// Connections and packets refused by the limits, by reason.
+    refused: LabeledCounter,
+}
+
+pub struct TrackerConfig {
+    /// How long a peer stays listed after its last announce.
+    pub expire: Duration,
+    /// How often peers are asked to announce, well within `expire`.
+    pub interval: Duration,
+    /// The shortest interval peers may announce at.
+    pub min_interval: Duration,
+    /// Peers returned by a peer list request that leaves it to the tracker.
+    pub default_numwant: usize,
+    /// Peers returned by a peer list request at most.
+    pub max_numwant: usize,
+    /// Where the swarms are saved, so that a restarted tracker picks them up.
+    pub state_file: Option<PathBuf>,
+    /// List peers at the address they announce instead of the source address
+    /// of their connection, for peers behind a proxy.
+    pub trust_client_addr: bool,
+    /// Connect back to a new peer and handshake with it before listing it.
+    pub verify_peers: bool,
+    /// Where to serve the JSON status and admin endpoint, if anywhere.
+    pub admin_addr: Option<SocketAddr>,
+    /// Bearer token the admin actions require. Without one they are disabled.
+    pub admin_token: Option<String>,
+    /// Where to serve Prometheus metrics, if anywhere.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Where to serve the HTTP tracker protocol, if anywhere.
+    pub http_addr: Option<SocketAddr>,
+    /// Other trackers of the federation, exchanging their swarms with this one.
+    pub peer_trackers: Vec<SocketAddr>,
+    /// Secret gossip must carry to be merged, besides coming from a peer
+    /// tracker.
+    pub federation_secret: Option<String>,
+    /// Info hashes of the only swarms peers may announce to.
+    pub allowlist_file: Option<PathBuf>,
+    /// Users and their passkeys. With users, announces need a passkey.
+    pub users_file: Option<PathBuf>,
+    /// Requests a source IP may make per second, over every protocol.
+    pub max_requests_per_sec: f64,
+    /// Connections open at once, of every source.
+    pub max_connections: usize,
+    /// Connections a source IP may hold open at once, subscriptions aside.
+    pub max_connections_per_ip: usize,
+    /// Subscriptions a source IP may hold open at once.
+    pub max_subscriptions_per_ip: usize,
+    /// How long a source going over its limits is refused.
+    pub ban: Duration,
+    /// Networks whose peers are listed to each other first.
+    pub locality_groups: Vec<Cidr>,
+    /// Networks of each site, whose peers are listed to each other first.
+    pub site_map_file: Option<PathBuf>,
+    /// Share of a peer list kept for remote peers, when there are any.
+    pub remote_fraction: f64,
+    /// Where to log swarm events as JSON lines, if anywhere.
+    pub event_log_file: Option<PathBuf>,
+    /// Size at which the event log is rotated.
+    pub event_log_max_bytes: u64,
+    /// Rotated event logs kept.
+    pub event_log_keep: usize,
+    /// Where to save uploaded torrent files, keeping them only in memory
+    /// when `None`.
+    pub torrent_dir: Option<PathBuf>,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        TrackerConfig::with_expire(Duration::from_secs(5))
+    }
+}
 
+impl TrackerConfig {
+    /// Ask peers to announce twice per expiry period, so that a single lost
+    /// announce does not get them dropped.
+    pub fn with_expire(expire: Duration) -> Self {
+        TrackerConfig {
+            expire,
+            interval: expire / 2,
+            min_interval: expire / 4,
+            default_numwant: 50,
+            max_numwant: 200,
+            state_file: None,
+            trust_client_addr: false,
+            verify_peers: false,
+            admin_addr: None,
+            admin_token: None,
+            metrics_addr: None,
+            http_addr: None,
+            peer_trackers: vec![],
+            federation_secret: None,
+            allowlist_file: None,
+            users_file: None,
+            max_requests_per_sec: 50.0,
+            max_connections: 256,
+            max_connections_per_ip: 8,
+            max_subscriptions_per_ip: 64,
+            ban: Duration::from_secs(60),
+            locality_groups: vec![],
+            site_map_file: None,
+            remote_fraction: 0.2,
+            event_log_file: None,
+            event_log_max_bytes: 10 << 20,
+            event_log_keep: 5,
+            torrent_dir: None,
+        }
+    }
+}
+
+/// A tracker and the state its listeners share, cloned for each of them.
+#[derive(Clone)]
 pub struct Tracker {
-    client_expire_times: Arc<Mutex<HashMap<SocketAddr, SystemTime>>>,
+    /// Every swarm the tracker has heard of, keyed by info hash.
+    swarms: Arc<Mutex<Swarms>>,
+    /// Swarms closed by an admin, refusing announces.
+    closed_swarms: Arc<Mutex<HashSet<InfoHash>>>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    /// Swarms expected to reach given peers, by info hash.
+    deployments: Arc<Mutex<deployment::Deployments>>,
+    access: Arc<access::Access>,
+    /// Torrent files uploaded, by info hash.
+    registry: Arc<registry::Registry>,
+    events: Arc<events::EventLog>,
+    limits: Arc<limits::Limits>,
+    locality: Arc<Locality>,
+    config: Arc<TrackerConfig>,
     thread_pool: ThreadPool,
     read_timeout: Duration,
+    metrics: Arc<TrackerMetrics>,
+    /// Identifies the tracker when it connects back to peers.
+    peer_id: PeerId,
+    /// Keys the connection ids handed to UDP clients.
+    udp_secret: [u8; 32],
 }
 
-const EXPIRE_SECONDS: f64 = 5.0;
+impl Default for Tracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Tracker {
     pub fn new() -> Self {
+        Tracker::with_config(TrackerConfig::default())
+    }
+
+    pub fn with_config(config: TrackerConfig) -> Self {
+        assert!(
+            config.min_interval <= config.interval && config.interval < config.expire,
+            "announce interval must be within the peer expiry"
+        );
         let thread_pool = ThreadPool::new(32);
+        let swarms = match &config.state_file {
+            Some(path) if path.exists() => {
+                let swarms = load_state(path);
+                println!("Restored {} swarms from {}", swarms.len(), path.display());
+                swarms
+            }
+            _ => HashMap::new(),
+        };
         Tracker {
-            client_expire_times: Arc::new(Mutex::new(HashMap::new())),
+            swarms: Arc::new(Mutex::new(swarms)),
+            closed_swarms: Arc::new(Mutex::new(HashSet::new())),
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            deployments: Arc::new(Mutex::new(HashMap::new())),
+            access: Arc::new(access::Access::load(
+                config.allowlist_file.as_deref(),
+                config.users_file.as_deref(),
+            )),
+            registry: Arc::new(registry::Registry::load(config.torrent_dir.as_deref())),
+            events: Arc::new(match &config.event_log_file {
+                Some(path) => {
+                    events::EventLog::open(path, config.event_log_max_bytes, config.event_log_keep)
+                }
+                None => events::EventLog::disabled(),
+            }),
+            limits: Arc::new(limits::Limits::new(
+                config.max_requests_per_sec,
+                config.max_connections,
+                config.max_connections_per_ip,
+                config.max_subscriptions_per_ip,
+                config.ban,
+            )),
+            locality: Arc::new(Locality::new(
+                config.locality_groups.clone(),
+                config.site_map_file.as_deref(),
+            )),
+            config: Arc::new(config),
             thread_pool,
             read_timeout: Duration::from_secs(1),
+            metrics: Arc::new(TrackerMetrics::default()),
+            peer_id: PeerId::random(),
+            udp_secret: rand::random(),
         }
     }
 
     pub fn start(&mut self, socket_addr: SocketAddr) {
-        let client_expire_times = Arc::clone(&self.client_expire_times);
-        std::thread::spawn(|| check_expire_loop(client_expire_times));
+        let swarms = Arc::clone(&self.swarms);
+        let expire = self.config.expire;
+        let metrics = Arc::clone(&self.metrics);
+        let events = Arc::clone(&self.events);
+        std::thread::spawn(move || check_expire_loop(swarms, expire, metrics, events));
+        let swarms = Arc::clone(&self.swarms);
+        let closed_swarms = Arc::clone(&self.closed_swarms);
+        let subscriptions = Arc::clone(&self.subscriptions);
+        let locality = Arc::clone(&self.locality);
+        let remote_fraction = self.config.remote_fraction;
+        std::thread::spawn(move || {
+            publish_loop(
+                swarms,
+                closed_swarms,
+                subscriptions,
+                locality,
+                remote_fraction,
+            )
+        });
+        let limits = Arc::clone(&self.limits);
+        std::thread::spawn(move || limits::forget_loop(limits));
+        if let Some(state_saver) = self.state_saver() {
+            std::thread::spawn(move || save_state_loop(state_saver));
+        }
+        if !self.config.peer_trackers.is_empty() {
+            let swarms = Arc::clone(&self.swarms);
+            let peer_trackers = self.config.peer_trackers.clone();
+            let secret = self.config.federation_secret.clone().unwrap_or_default();
+            std::thread::spawn(move || federation::gossip_loop(swarms, peer_trackers, secret));
+        }
+        if let Some(admin_addr) = self.config.admin_addr {
+            let listener = TcpListener::bind(admin_addr)
+                .unwrap_or_else(|_| panic!("admin listener cannot bind at {}", admin_addr));
+            println!("Admin endpoint listening on {}", admin_addr);
+            let admin = admin::Admin {
+                swarms: Arc::clone(&self.swarms),
+                closed_swarms: Arc::clone(&self.closed_swarms),
+                access: Arc::clone(&self.access),
+                events: Arc::clone(&self.events),
+                token: self.config.admin_token.clone(),
+            };
+            std::thread::spawn(move || admin::serve(listener, admin));
+        }
+        if let Some(http_addr) = self.config.http_addr {
+            let listener = TcpListener::bind(http_addr)
+                .unwrap_or_else(|_| panic!("HTTP listener cannot bind at {}", http_addr));
+            println!("HTTP tracker listening on {}", http_addr);
+            let tracker = self.clone();
+            std::thread::spawn(move || http::serve(listener, tracker));
+        }
+        if let Some(metrics_addr) = self.config.metrics_addr {
+            let swarms = Arc::clone(&self.swarms);
+            let subscriptions = Arc::clone(&self.subscriptions);
+            let metrics = Arc::clone(&self.metrics);
+            crate::metrics::serve(metrics_addr, move || {
+                render_metrics(&swarms, &subscriptions, &metrics)
+            });
+        }
 
         let listener = TcpListener::bind(socket_addr)
-            .expect(format!("listener cannot bind at {}", socket_addr).as_str());
+            .unwrap_or_else(|_| panic!("listener cannot bind at {}", socket_addr));
+        // UDP trackers are reached at the same address
+        let socket_addr = listener.local_addr().unwrap();
+        let socket = UdpSocket::bind(socket_addr)
+            .unwrap_or_else(|_| panic!("UDP socket cannot bind at {}", socket_addr));
+        let tracker = self.clone();
+        std::thread::spawn(move || udp::serve(socket, tracker));
 
-        println!("Tracker listening on {}", socket_addr);
+        println!("Tracker listening on {} over TCP and UDP", socket_addr);
 
-        for stream in listener.incoming() {
-            let stream = stream.expect("incoming stream error");
+        // out of file descriptors under a flood, the next accept may succeed
+        for stream in listener.incoming().filter_map(|x| x.ok()) {
+            let connection = match self.open_connection(&stream) {
+                Some(connection) => connection,
+                None => continue,
+            };
             println!("Incoming connection from {}", stream.peer_addr().unwrap());
             stream
                 .set_read_timeout(Some(self.read_timeout))
                 .expect("stream set read timeout error");
-            self.handle_client(stream);
+            let mut tracker = self.clone();
+            self.thread_pool
+                .execute(move || tracker.handle_client(stream, connection));
         }
     }
 
-    fn handle_client(&mut self, mut stream: TcpStream) {
+    /// Count a new connection against the limits of its source, or refuse it.
+    fn open_connection(&self, stream: &TcpStream) -> Option<limits::Connection> {
+        let source = stream.peer_addr().ok()?;
+        self.limits
+            .open_connection(source.ip())
+            .map_err(|reason| self.metrics.refused.inc(reason))
+            .ok()
+    }
+
+    /// Saves the swarms to the state file from outside of the tracker loops,
+    /// if the tracker has one.
+    pub fn state_saver(&self) -> Option<StateSaver> {
+        Some(StateSaver {
+            swarms: Arc::clone(&self.swarms),
+            path: self.config.state_file.clone()?,
+        })
+    }
+
+    /// Serve the request of a connection, which is counted against the limits
+    /// as long as `connection` lives: until the request is answered, or the
+    /// subscription it opens is dropped.
+    fn handle_client(&mut self, mut stream: TcpStream, connection: limits::Connection) {
         use crate::requests::request::Type;
-        let request = if let Ok(request) = crate::read_request(&mut stream) {
-            request
+        let request = match crate::read_request(&mut stream) {
+            Ok(request) => request,
+            Err(e)
+                if e.get_ref()
+                    .is_some_and(|e| e.is::<crate::RequestTooLarge>()) =>
+            {
+                if let Ok(source) = stream.peer_addr() {
+                    println!("Refusing request from {}: {}", source, e);
+                }
+                self.metrics.requests.inc("too_large");
+                self.handle_bad_request(&mut stream);
+                return;
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                self.metrics.requests.inc("malformed");
+                self.handle_bad_request(&mut stream);
+                return;
+            }
+            Err(_) => return,
+        };
+        self.metrics.requests.inc(match &request.r#type {
+            Some(Type::Join(_)) => "join",
+            Some(Type::ActiveProof(_)) => "active_proof",
+            Some(Type::PeerList(_)) => "peer_list",
+            Some(Type::Scrape(_)) => "scrape",
+            Some(Type::Subscribe(_)) => "subscribe",
+            Some(Type::Gossip(_)) => "gossip",
+            Some(Type::Deploy(_)) => "deploy",
+            Some(Type::DeploymentStatus(_)) => "deployment_status",
+            Some(Type::UploadTorrent(_)) => "upload_torrent",
+            Some(Type::FetchTorrent(_)) => "fetch_torrent",
+            _ => "other",
+        });
+
+        match request.r#type {
+            Some(Type::Join(client)) => match (
+                InfoHash::from_bytes(&client.info_hash),
+                PeerId::from_bytes(&client.peer_id),
+                self.client_addr(&stream, &client.listening_addr),
+            ) {
+                (Some(info_hash), Some(peer_id), Some(client_addr)) => {
+                    let join = Announce {
+                        listening_addr: client_addr,
+                        info_hash,
+                        peer_id,
+                        event: Event::Started,
+                        transfer: client.transfer.unwrap_or_default(),
+                        passkey: client.passkey,
+                    };
+                    self.handle_peer_joining_request(&mut stream, join)
+                }
+                _ => self.handle_bad_request(&mut stream),
+            },
+            Some(Type::ActiveProof(client)) => match (
+                InfoHash::from_bytes(&client.info_hash),
+                PeerId::from_bytes(&client.peer_id),
+                self.client_addr(&stream, &client.listening_addr),
+            ) {
+                (Some(info_hash), Some(peer_id), Some(client_addr)) => {
+                    let active_proof = Announce {
+                        listening_addr: client_addr,
+                        info_hash,
+                        peer_id,
+                        event: client.event(),
+                        transfer: client.transfer.unwrap_or_default(),
+                        passkey: client.passkey,
+                    };
+                    self.handle_active_proof_request(&mut stream, active_proof)
+                }
+                _ => self.handle_bad_request(&mut stream),
+            },
+            Some(Type::PeerList(peer_list)) => match InfoHash::from_bytes(&peer_list.info_hash) {
+                Some(info_hash) => self.handle_peer_list_request(
+                    &mut stream,
+                    info_hash,
+                    PeerId::from_bytes(&peer_list.peer_id),
+                    peer_list.numwant as usize,
+                    &peer_list.passkey,
+                ),
+                None => self.handle_bad_request(&mut stream),
+            },
+            Some(Type::Scrape(scrape)) => {
+                let info_hashes: Option<Vec<InfoHash>> = scrape
+                    .info_hashes
+                    .iter()
+                    .map(|info_hash| InfoHash::from_bytes(info_hash))
+                    .collect();
+                match info_hashes {
+                    Some(info_hashes) => {
+                        self.handle_scrape_request(&mut stream, info_hashes, &scrape.passkey)
+                    }
+                    None => self.handle_bad_request(&mut stream),
+                }
+            }
+            Some(Type::Subscribe(subscribe)) => match (
+                InfoHash::from_bytes(&subscribe.info_hash),
+                PeerId::from_bytes(&subscribe.peer_id),
+            ) {
+                (Some(info_hash), Some(peer_id)) => self.handle_subscribe_request(
+                    stream,
+                    connection,
+                    info_hash,
+                    peer_id,
+                    &subscribe.passkey,
+                ),
+                _ => self.handle_bad_request(&mut stream),
+            },
+            Some(Type::Gossip(gossip)) => self.handle_gossip_request(&mut stream, gossip),
+            Some(Type::Deploy(deploy)) => {
+                let targets: Result<Vec<SocketAddr>, _> =
+                    deploy.targets.iter().map(|target| target.parse()).collect();
+                match (InfoHash::from_bytes(&deploy.info_hash), targets) {
+                    (Some(info_hash), Ok(targets)) => self.handle_deploy_request(
+                        &mut stream,
+                        info_hash,
+                        targets,
+                        deploy.count as usize,
+                        &deploy.passkey,
+                        deploy.replace,
+                    ),
+                    _ => self.handle_bad_request(&mut stream),
+                }
+            }
+            Some(Type::DeploymentStatus(status)) => match InfoHash::from_bytes(&status.info_hash) {
+                Some(info_hash) => {
+                    self.handle_deployment_status_request(&mut stream, info_hash, &status.passkey)
+                }
+                None => self.handle_bad_request(&mut stream),
+            },
+            Some(Type::UploadTorrent(upload)) => {
+                self.handle_upload_torrent_request(&mut stream, upload)
+            }
+            Some(Type::FetchTorrent(fetch)) => match InfoHash::from_bytes(&fetch.info_hash) {
+                Some(info_hash) => {
+                    self.handle_fetch_torrent_request(&mut stream, info_hash, &fetch.passkey)
+                }
+                None => self.handle_bad_request(&mut stream),
+            },
+            _ => self.handle_bad_request(&mut stream),
+        }
+    }
+
+    /// The address a peer is listed under: the source IP of its connection
+    /// with the port it says it listens on, so that nobody can list another
+    /// host. `None` when the announced address is not one.
+    fn client_addr(&self, stream: &TcpStream, listening_addr: &str) -> Option<SocketAddr> {
+        let listening_addr: SocketAddr = listening_addr.parse().ok()?;
+        if self.config.trust_client_addr {
+            return Some(listening_addr);
+        }
+        Some(SocketAddr::new(
+            stream.peer_addr().ok()?.ip(),
+            listening_addr.port(),
+        ))
+    }
+
+    fn handle_bad_request(&mut self, stream: &mut TcpStream) {
+        println!("Rejecting bad request from {}", stream.peer_addr().unwrap());
+        crate::send_message(stream, crate::get_bad_response()).ok();
+    }
+
+    fn handle_peer_joining_request(&mut self, stream: &mut TcpStream, join: Announce) {
+        println!(
+            "Handling peer join request from {} for swarm {}, peer {} is listening at {}",
+            stream.peer_addr().unwrap(),
+            join.info_hash,
+            join.peer_id,
+            join.listening_addr
+        );
+        let announced = self.announce(join);
+        self.send_announce_response(stream, announced);
+    }
+
+    fn handle_active_proof_request(&mut self, stream: &mut TcpStream, active_proof: Announce) {
+        println!(
+            "handling active proof request from {} for swarm {}, peer {} listening at {}",
+            stream.peer_addr().unwrap(),
+            active_proof.info_hash,
+            active_proof.peer_id,
+            active_proof.listening_addr
+        );
+        let announced = self.announce(active_proof);
+        self.send_announce_response(stream, announced);
+    }
+
+    fn send_announce_response(&self, stream: &mut TcpStream, announced: Result<(), &str>) {
+        let response = match announced {
+            Ok(()) => crate::get_announce_response(self.config.interval, self.config.min_interval),
+            Err(_) => crate::get_bad_response(),
+        };
+        crate::send_message(stream, response).ok();
+    }
+
+    /// Record what a peer announced about its part in a swarm, or tell why
+    /// the swarm is closed to it.
+    fn announce(&self, announce: Announce) -> Result<(), &'static str> {
+        let Announce {
+            listening_addr: client_listening_addr,
+            info_hash,
+            peer_id,
+            event,
+            transfer,
+            passkey,
+        } = announce;
+        let evicted = self
+            .swarms
+            .lock()
+            .unwrap()
+            .get(&info_hash)
+            .is_some_and(|swarm| swarm.evicted.contains_key(&peer_id));
+        let refusal = if self.closed_swarms.lock().unwrap().contains(&info_hash) {
+            Err("swarm closed")
+        } else if evicted {
+            Err("evicted")
         } else {
-            return;
+            self.access.check(info_hash, &passkey)
         };
+        let user = refusal.inspect_err(|reason| {
+            println!(
+                "Refusing {} announcing to swarm {}: {}",
+                peer_id, info_hash, reason
+            );
+            self.events.record(
+                "reject",
+                info_hash,
+                peer_id,
+                json!({ "address": client_listening_addr.to_string(), "reason": reason }),
+            );
+        })?;
+        if let Some(user) = user {
+            self.access
+                .record(&user, info_hash, peer_id, event, &transfer);
+        }
+        self.record_deployment(info_hash, peer_id, client_listening_addr, event, &transfer);
+        let mut swarms = self.swarms.lock().unwrap();
+        let swarm = swarms.entry(info_hash).or_default();
+        let address = client_listening_addr.to_string();
+        if event == Event::Stopped {
+            println!("{} left swarm {}", peer_id, info_hash);
+            if swarm.depart(peer_id).is_some() {
+                let details = json!({ "address": address, "reason": "stopped" });
+                self.events.record("leave", info_hash, peer_id, details);
+            }
+            return Ok(());
+        }
 
-        match request.r#type.unwrap() {
-            Type::Join(client) => self
-                .handle_peer_joining_request(&mut stream, client.listening_addr.parse().unwrap()),
-            Type::ActiveProof(client) => self
-                .handle_active_proof_request(&mut stream, client.listening_addr.parse().unwrap()),
-            Type::PeerList(_) => self.handle_peer_list_request(&mut stream),
-            _ => {}
+        if event == Event::Completed {
+            println!("{} completed its download of {}", peer_id, info_hash);
+            swarm.completed += 1;
+            let details = json!({ "address": address });
+            self.events.record("complete", info_hash, peer_id, details);
+        }
+        if self.config.verify_peers && !swarm.peers.contains_key(&peer_id) {
+            // listed once it answered, its next announce lands otherwise
+            let candidate = Candidate {
+                info_hash,
+                peer_id,
+                addr: client_listening_addr,
+                transfer,
+                last_seen: SystemTime::now(),
+            };
+            self.verify_later(swarm, candidate);
+            return Ok(());
         }
+        if !swarm.peers.contains_key(&peer_id) {
+            let details = json!({ "address": address, "left": transfer.left });
+            self.events.record("join", info_hash, peer_id, details);
+        }
+        swarm.insert(peer_id, client_listening_addr, transfer);
+        Ok(())
     }
 
-    fn handle_peer_joining_request(
+    /// Connect back to a peer in the background, unless it already is, and
+    /// list it once it answered.
+    fn verify_later(&self, swarm: &mut Swarm, candidate: Candidate) {
+        if swarm.unverified.insert(candidate.peer_id) {
+            let swarms = Arc::clone(&self.swarms);
+            let events = Arc::clone(&self.events);
+            let tracker_id = self.peer_id;
+            self.thread_pool
+                .execute(move || verify_peer(swarms, events, tracker_id, candidate));
+        }
+    }
+
+    fn handle_peer_list_request(
         &mut self,
         stream: &mut TcpStream,
-        client_listening_addr: SocketAddr,
+        info_hash: InfoHash,
+        peer_id: Option<PeerId>,
+        numwant: usize,
+        passkey: &str,
     ) {
         println!(
-            "Handling peer join request from {}, he is listening at {}",
+            "handling peer list request from {} for swarm {}",
             stream.peer_addr().unwrap(),
-            client_listening_addr
+            info_hash
         );
-        self.client_expire_times
+        let source = stream.peer_addr().unwrap().ip();
+        let response = match self.access.check(info_hash, passkey) {
+            Ok(_) => self.get_peer_list_response(info_hash, peer_id, numwant, source),
+            Err(reason) => {
+                println!("Refusing peer list of swarm {}: {}", info_hash, reason);
+                crate::get_bad_response()
+            }
+        };
+        crate::send_message(stream, response).ok();
+    }
+
+    /// A sample of the swarm, the closest peers first, leaving out the peer
+    /// asking for it.
+    fn get_peer_list_response(
+        &self,
+        info_hash: InfoHash,
+        client_id: Option<PeerId>,
+        numwant: usize,
+        source: IpAddr,
+    ) -> responses::Response {
+        use responses::response;
+        use responses::response::Type;
+
+        let peers = self
+            .sample_peers(info_hash, client_id, numwant, source, |_| true)
+            .into_iter()
+            .map(|(peer_id, addr, proximity)| response::PeerAddress {
+                peer_id: peer_id.0.to_vec(),
+                address: addr.to_string(),
+                proximity: proximity as i32,
+            })
+            .collect();
+
+        let mut response = responses::Response::default();
+        response.r#type = Some(Type::PeerList(response::PeerList { peers }));
+        response
+    }
+
+    /// Up to `numwant` peers of a swarm that `keep` accepts, leaving out the
+    /// peer asking from `source`, and how close each is to it. The closest
+    /// come first, with a share of remote ones mixed in. A `numwant` of 0
+    /// leaves the count to the tracker.
+    fn sample_peers<F>(
+        &self,
+        info_hash: InfoHash,
+        client_id: Option<PeerId>,
+        numwant: usize,
+        source: IpAddr,
+        keep: F,
+    ) -> Vec<(PeerId, SocketAddr, Proximity)>
+    where
+        F: Fn(&SocketAddr) -> bool,
+    {
+        let numwant = match numwant {
+            0 => self.config.default_numwant,
+            numwant => numwant.min(self.config.max_numwant),
+        };
+        let candidates = self
+            .swarms
             .lock()
             .unwrap()
-            .insert(client_listening_addr, SystemTime::now());
-        crate::send_message(stream, crate::get_ok_response());
+            .get(&info_hash)
+            .map(|swarm| {
+                let client_ip = swarm.ip_of(client_id, source);
+                swarm
+                    .peers
+                    .iter()
+                    .filter(|(peer_id, peer)| Some(**peer_id) != client_id && keep(&peer.addr))
+                    .map(|(peer_id, peer)| {
+                        let proximity = self.locality.proximity(client_ip, peer.addr.ip());
+                        (proximity, (*peer_id, peer.addr, proximity))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        locality::pick(candidates, numwant, self.config.remote_fraction)
     }
 
-    fn handle_active_proof_request(
+    /// Send the subscriber every peer of the swarm, then keep its connection
+    /// for `publish_loop` to push the changes on.
+    fn handle_subscribe_request(
         &mut self,
-        stream: &mut TcpStream,
-        client_listening_addr: SocketAddr,
+        mut stream: TcpStream,
+        connection: limits::Connection,
+        info_hash: InfoHash,
+        peer_id: PeerId,
+        passkey: &str,
     ) {
         println!(
-            "handling active proof request from {}, client listening at {}",
+            "{} at {} subscribed to swarm {}",
+            peer_id,
             stream.peer_addr().unwrap(),
-            client_listening_addr
+            info_hash
         );
-        self.client_expire_times
+        if self.closed_swarms.lock().unwrap().contains(&info_hash)
+            || self.access.check(info_hash, passkey).is_err()
+        {
+            crate::send_message(&mut stream, crate::get_bad_response()).ok();
+            return;
+        }
+        let source = stream.peer_addr().unwrap().ip();
+        let (ip, peers) = self
+            .swarms
             .lock()
             .unwrap()
-            .insert(client_listening_addr, SystemTime::now());
-        crate::send_message(stream, crate::get_ok_response());
+            .get(&info_hash)
+            .map(|swarm| {
+                let ip = swarm.ip_of(Some(peer_id), source);
+                let peers = swarm
+                    .peers
+                    .iter()
+                    .filter(|(other_id, _)| **other_id != peer_id)
+                    .map(|(other_id, peer)| {
+                        let proximity = self.locality.proximity(ip, peer.addr.ip());
+                        (*other_id, peer.addr, proximity)
+                    })
+                    .collect();
+                (ip, peers)
+            })
+            .unwrap_or((source, vec![]));
+        // a subscriber that stops reading must not hold up the others
+        stream
+            .set_write_timeout(Some(Duration::from_secs(1)))
+            .expect("stream set write timeout error");
+        let connection = match connection.into_subscription() {
+            Ok(connection) => connection,
+            Err(reason) => {
+                println!("Refusing subscription of {}: {}", peer_id, reason);
+                self.metrics.refused.inc(reason);
+                crate::send_message(&mut stream, crate::get_bad_response()).ok();
+                return;
+            }
+        };
+        let snapshot = crate::get_swarm_delta_response(peers, vec![], self.config.remote_fraction);
+        if crate::send_message(&mut stream, snapshot).is_ok() {
+            self.subscriptions
+                .lock()
+                .unwrap()
+                .entry(info_hash)
+                .or_default()
+                .push(Subscription {
+                    peer_id,
+                    ip,
+                    stream,
+                    _connection: connection,
+                });
+        }
     }
 
-    fn handle_peer_list_request(&mut self, stream: &mut TcpStream) {
-        println!(
-            "handling peer list request from {}",
-            stream.peer_addr().unwrap()
-        );
-        let response = self.get_peer_list_response();
-        crate::send_message(stream, response);
+    fn handle_scrape_request(
+        &mut self,
+        stream: &mut TcpStream,
+        info_hashes: Vec<InfoHash>,
+        passkey: &str,
+    ) {
+        let source = stream.peer_addr().unwrap();
+        println!("handling scrape request from {}", source);
+        let response = match self.scrape_scope(info_hashes, passkey) {
+            Ok(info_hashes) => self.get_scrape_response(info_hashes),
+            Err(reason) => {
+                println!("Refusing scrape from {}: {}", source, reason);
+                crate::get_bad_response()
+            }
+        };
+        crate::send_message(stream, response).ok();
     }
 
-    fn get_peer_list_response(&self) -> responses::Response {
+    /// The swarms a scrape may see: those it names, as long as it may
+    /// announce to every one of them, or every swarm the allowlist allows.
+    fn scrape_scope(
+        &self,
+        info_hashes: Vec<InfoHash>,
+        passkey: &str,
+    ) -> Result<Vec<InfoHash>, &'static str> {
+        if info_hashes.is_empty() {
+            self.access.check_user(passkey)?;
+            let swarms = self.swarms.lock().unwrap();
+            return Ok(swarms
+                .keys()
+                .filter(|info_hash| self.access.allows(info_hash))
+                .cloned()
+                .collect());
+        }
+        for &info_hash in &info_hashes {
+            self.access.check(info_hash, passkey)?;
+        }
+        Ok(info_hashes)
+    }
+
+    fn get_scrape_response(&self, info_hashes: Vec<InfoHash>) -> responses::Response {
         use responses::response;
         use responses::response::Type;
+        let swarms = self.swarm_stats(info_hashes);
         let mut response = responses::Response::default();
-        let addresses = self
-            .client_expire_times
-            .lock()
-            .unwrap()
-            .keys()
-            .map(|peer_addr| peer_addr.to_string())
-            .collect();
-
-        response.r#type = Some(Type::PeerList(response::PeerList { addresses }));
+        response.r#type = Some(Type::Scrape(response::Scrape { swarms }));
         response
     }
+
+    /// Statistics of the given swarms.
+    fn swarm_stats(&self, info_hashes: Vec<InfoHash>) -> Vec<SwarmStats> {
+        use responses::response;
+        let swarms = self.swarms.lock().unwrap();
+        info_hashes
+            .into_iter()
+            .map(|info_hash| {
+                let mut stats = SwarmStats {
+                    info_hash: info_hash.0.to_vec(),
+                    ..Default::default()
+                };
+                if let Some(swarm) = swarms.get(&info_hash) {
+                    for (peer_id, peer) in &swarm.peers {
+                        let transfer = &peer.transfer;
+                        if transfer.left == 0 {
+                            stats.seeders += 1;
+                        } else {
+                            stats.leechers += 1;
+                        }
+                        stats.uploaded += transfer.uploaded;
+                        stats.downloaded += transfer.downloaded;
+                        stats.peers.push(response::PeerStats {
+                            address: peer.addr.to_string(),
+                            uploaded: transfer.uploaded,
+                            downloaded: transfer.downloaded,
+                            left: transfer.left,
+                            peer_id: peer_id.0.to_vec(),
+                        });
+                    }
+                    stats.completed = swarm.completed;
+                }
+                stats
+            })
+            .collect()
+    }
+}
+
+/// A peer announced, or gossiped by another tracker, that is only listed once
+/// it answers.
+struct Candidate {
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    addr: SocketAddr,
+    transfer: Transfer,
+    /// When it last announced, wherever it did.
+    last_seen: SystemTime,
 }
 
-fn check_expire_loop(client_expire_times: Arc<Mutex<HashMap<SocketAddr, SystemTime>>>) {
+/// Connect back to a peer and handshake with it, listing it if it answers for
+/// the swarm it announced to, under the id it announced.
+fn verify_peer(
+    swarms: Arc<Mutex<Swarms>>,
+    events: Arc<events::EventLog>,
+    tracker_id: PeerId,
+    candidate: Candidate,
+) {
+    let Candidate {
+        info_hash,
+        peer_id,
+        addr,
+        transfer,
+        last_seen,
+    } = candidate;
+    let verified =
+        TcpStream::connect_timeout(&addr, Duration::from_secs(2)).and_then(|mut stream| {
+            stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+            match crate::handshake(&mut stream, info_hash, tracker_id)? {
+                answered_id if answered_id == peer_id => Ok(()),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "another peer answered",
+                )),
+            }
+        });
+
+    let mut swarms = swarms.lock().unwrap();
+    let swarm = swarms.entry(info_hash).or_default();
+    if !swarm.unverified.remove(&peer_id) {
+        // it left while being verified
+        return;
+    }
+    match verified {
+        Ok(()) => {
+            println!("Verified {} at {} of swarm {}", peer_id, addr, info_hash);
+            let details = json!({ "address": addr.to_string(), "left": transfer.left });
+            events.record("join", info_hash, peer_id, details);
+            swarm.insert_seen(peer_id, addr, transfer, last_seen);
+        }
+        Err(e) => println!(
+            "Not listing {} at {} of swarm {}: {}",
+            peer_id, addr, info_hash, e
+        ),
+    }
+}
+
+fn check_expire_loop(
+    swarms: Arc<Mutex<Swarms>>,
+    expire: Duration,
+    metrics: Arc<TrackerMetrics>,
+    events: Arc<events::EventLog>,
+) {
     loop {
-        client_expire_times
-            .lock()
-            .unwrap()
-            .retain(|addr, expire_time| {
-                if !(expire_time.elapsed().unwrap().as_secs_f64() < EXPIRE_SECONDS) {
-                    println!("{} expire, dropping it", addr);
+        let mut swarms = swarms.lock().unwrap();
+        for (info_hash, swarm) in swarms.iter_mut() {
+            swarm.peers.retain(|peer_id, peer| {
+                let alive = peer.last_seen.elapsed().unwrap_or_default() < expire;
+                if !alive {
+                    println!("{} of swarm {} expire, dropping it", peer_id, info_hash);
+                    metrics.expirations.fetch_add(1, Ordering::SeqCst);
+                    let details = json!({ "address": peer.addr.to_string() });
+                    events.record("expire", *info_hash, *peer_id, details);
                 }
-                expire_time.elapsed().unwrap().as_secs_f64() < EXPIRE_SECONDS
+                alive
             });
+            swarm
+                .departed
+                .retain(|_, departed| departed.elapsed().unwrap_or_default() < expire);
+            swarm
+                .evicted
+                .retain(|_, evicted| evicted.elapsed().unwrap_or_default() < expire);
+        }
+        // a swarm without peers is only worth keeping for its statistics,
+        // until the other trackers learn who left it, or while it refuses
+        // evicted peers
+        swarms.retain(|_, swarm| {
+            !swarm.peers.is_empty()
+                || swarm.completed > 0
+                || !swarm.departed.is_empty()
+                || !swarm.evicted.is_empty()
+        });
+        drop(swarms);
         std::thread::sleep(Duration::from_millis(500));
     }
 }
+
+/// Push the peers that joined and left each subscribed swarm since the last
+/// round, and an empty delta to subscriptions that had nothing for a while.
+/// Subscribers of a closed swarm are told everyone left, then let go.
+fn publish_loop(
+    swarms: Arc<Mutex<Swarms>>,
+    closed_swarms: Arc<Mutex<HashSet<InfoHash>>>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    locality: Arc<Locality>,
+    remote_fraction: f64,
+) {
+    let mut published: HashMap<InfoHash, HashMap<PeerId, SocketAddr>> = HashMap::new();
+    let mut last_push: HashMap<InfoHash, Instant> = HashMap::new();
+    loop {
+        std::thread::sleep(Duration::from_millis(250));
+        let info_hashes: Vec<InfoHash> = subscriptions.lock().unwrap().keys().cloned().collect();
+        for info_hash in info_hashes {
+            let current: HashMap<PeerId, SocketAddr> = swarms
+                .lock()
+                .unwrap()
+                .get(&info_hash)
+                .map(|swarm| {
+                    swarm
+                        .peers
+                        .iter()
+                        .map(|(peer_id, peer)| (*peer_id, peer.addr))
+                        .collect()
+                })
+                .unwrap_or_default();
+            let previous = published.remove(&info_hash).unwrap_or_default();
+            // a peer back at another address leaves and joins again
+            let joined: Vec<(PeerId, SocketAddr)> = current
+                .iter()
+                .filter(|(peer_id, addr)| previous.get(peer_id) != Some(addr))
+                .map(|(peer_id, addr)| (*peer_id, *addr))
+                .collect();
+            let left: Vec<PeerId> = previous
+                .iter()
+                .filter(|(peer_id, addr)| current.get(peer_id) != Some(addr))
+                .map(|(peer_id, _)| *peer_id)
+                .collect();
+            published.insert(info_hash, current);
+
+            let quiet = last_push
+                .get(&info_hash)
+                .is_none_or(|pushed| pushed.elapsed() >= SUBSCRIPTION_KEEPALIVE);
+            if joined.is_empty() && left.is_empty() && !quiet {
+                continue;
+            }
+            last_push.insert(info_hash, Instant::now());
+            let mut subscriptions = subscriptions.lock().unwrap();
+            let subscribers = subscriptions.entry(info_hash).or_default();
+            subscribers.retain_mut(|subscription| {
+                let joined = joined
+                    .iter()
+                    .filter(|(peer_id, _)| *peer_id != subscription.peer_id)
+                    .map(|(peer_id, addr)| {
+                        let proximity = locality.proximity(subscription.ip, addr.ip());
+                        (*peer_id, *addr, proximity)
+                    })
+                    .collect();
+                let delta = crate::get_swarm_delta_response(joined, left.clone(), remote_fraction);
+                crate::send_message(&mut subscription.stream, delta).is_ok()
+            });
+            if subscribers.is_empty() || closed_swarms.lock().unwrap().contains(&info_hash) {
+                subscriptions.remove(&info_hash);
+                published.remove(&info_hash);
+                last_push.remove(&info_hash);
+            }
+        }
+    }
+}
+
+fn render_metrics(
+    swarms: &Mutex<Swarms>,
+    subscriptions: &Mutex<Subscriptions>,
+    metrics: &TrackerMetrics,
+) -> Exposition {
+    let mut exposition = Exposition::default();
+    exposition.labeled(
+        "p2p_tracker_requests_total",
+        "counter",
+        "Requests received, by type.",
+        "type",
+        metrics.requests.values(),
+    );
+    exposition.counter(
+        "p2p_tracker_expirations_total",
+        "Peers dropped for not announcing in time.",
+        metrics.expirations.load(Ordering::SeqCst),
+    );
+    exposition.labeled(
+        "p2p_tracker_refused_total",
+        "counter",
+        "Connections and packets refused by the limits, by reason.",
+        "reason",
+        metrics.refused.values(),
+    );
+    exposition.gauge(
+        "p2p_tracker_subscriptions",
+        "Connections held open to push swarm changes on.",
+        subscriptions
+            .lock()
+            .unwrap()
+            .values()
+            .map(Vec::len)
+            .sum::<usize>() as u64,
+    );
+    let swarms = swarms.lock().unwrap();
+    exposition.gauge(
+        "p2p_tracker_swarms",
+        "Swarms the tracker knows of.",
+        swarms.len() as u64,
+    );
+    exposition.labeled(
+        "p2p_tracker_active_peers",
+        "gauge",
+        "Peers listed, by swarm.",
+        "swarm",
+        swarms
+            .iter()
+            .map(|(info_hash, swarm)| (info_hash.to_string(), swarm.peers.len() as u64))
+            .collect(),
+    );
+    exposition
+}
+
+pub struct StateSaver {
+    swarms: Arc<Mutex<Swarms>>,
+    path: PathBuf,
+}
+
+impl StateSaver {
+    /// Write a snapshot of every swarm. Peers are saved with how long ago they
+    /// last announced, or left, so that the downtime of the tracker does not
+    /// count against them once restored.
+    pub fn save(&self) -> io::Result<()> {
+        let now = SystemTime::now();
+        let swarms: serde_json::Map<String, Value> = self
+            .swarms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(info_hash, swarm)| {
+                let peers: Vec<Value> = swarm
+                    .peers
+                    .iter()
+                    .map(|(peer_id, peer)| {
+                        let age = now.duration_since(peer.last_seen).unwrap_or_default();
+                        let transfer = &peer.transfer;
+                        json!({
+                            "peer_id": peer_id.to_string(),
+                            "address": peer.addr.to_string(),
+                            "age_ms": age.as_millis() as u64,
+                            "uploaded": transfer.uploaded,
+                            "downloaded": transfer.downloaded,
+                            "left": transfer.left,
+                        })
+                    })
+                    .collect();
+                let departed: Vec<Value> = swarm
+                    .departed
+                    .iter()
+                    .map(|(peer_id, departed)| {
+                        let age = now.duration_since(*departed).unwrap_or_default();
+                        json!({
+                            "peer_id": peer_id.to_string(),
+                            "age_ms": age.as_millis() as u64,
+                        })
+                    })
+                    .collect();
+                let state = json!({
+                    "completed": swarm.completed,
+                    "peers": peers,
+                    "departed": departed,
+                });
+                (info_hash.to_string(), state)
+            })
+            .collect();
+
+        // written aside and renamed, so a crash never leaves half a snapshot
+        let temporary = self.path.with_extension("tmp");
+        std::fs::write(&temporary, Value::Object(swarms).to_string())?;
+        std::fs::rename(&temporary, &self.path)
+    }
+}
+
+fn save_state_loop(state_saver: StateSaver) {
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+        if let Err(e) = state_saver.save() {
+            println!("Saving tracker state failed: {}", e);
+        }
+    }
+}
+
+/// Restore the swarms saved by `StateSaver`. Whatever cannot be parsed is
+/// skipped with a warning rather than keeping the tracker from starting, and
+/// a file that cannot be parsed at all is set aside, not to be overwritten.
+fn load_state(path: &Path) -> Swarms {
+    let state = std::fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|content| serde_json::from_slice(&content).map_err(|e| e.to_string()));
+    let state = match state {
+        Ok(Value::Object(state)) => state,
+        _ => {
+            let aside = path.with_extension("bad");
+            println!(
+                "Warning: ignoring the unreadable tracker state file {}, moved to {}",
+                path.display(),
+                aside.display()
+            );
+            std::fs::rename(path, &aside).ok();
+            return HashMap::new();
+        }
+    };
+    let now = SystemTime::now();
+    let mut swarms = HashMap::new();
+    for (info_hash, state) in &state {
+        match InfoHash::from_hex(info_hash) {
+            Some(info_hash) => {
+                swarms.insert(info_hash, load_swarm(info_hash, state, now));
+            }
+            None => println!("Warning: skipping saved swarm {}: bad info hash", info_hash),
+        }
+    }
+    swarms
+}
+
+fn load_swarm(info_hash: InfoHash, state: &Value, now: SystemTime) -> Swarm {
+    let mut swarm = Swarm {
+        completed: state
+            .get("completed")
+            .and_then(Value::as_u64)
+            .unwrap_or_else(|| {
+                println!("Warning: bad completed count of saved swarm {}", info_hash);
+                0
+            }),
+        ..Default::default()
+    };
+    let entries = |key| {
+        state
+            .get(key)
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+    };
+    for peer in entries("peers") {
+        match load_peer(peer, now) {
+            Some((peer_id, peer)) => {
+                swarm.peers.insert(peer_id, peer);
+            }
+            None => println!(
+                "Warning: skipping saved peer {} of swarm {}",
+                peer, info_hash
+            ),
+        }
+    }
+    // missing from the files of older trackers
+    for departed in entries("departed") {
+        let peer_id = departed
+            .get("peer_id")
+            .and_then(Value::as_str)
+            .and_then(PeerId::from_hex);
+        let departed_at = departed
+            .get("age_ms")
+            .and_then(Value::as_u64)
+            .and_then(|age_ms| now.checked_sub(Duration::from_millis(age_ms)));
+        match (peer_id, departed_at) {
+            (Some(peer_id), Some(departed_at)) => {
+                swarm.departed.insert(peer_id, departed_at);
+            }
+            _ => println!(
+                "Warning: skipping saved departure {} of swarm {}",
+                departed, info_hash
+            ),
+        }
+    }
+    swarm
+}
+
+fn load_peer(peer: &Value, now: SystemTime) -> Option<(PeerId, SwarmPeer)> {
+    let field = |key| peer.get(key).and_then(Value::as_u64);
+    let peer_id = PeerId::from_hex(peer.get("peer_id")?.as_str()?)?;
+    let addr: SocketAddr = peer.get("address")?.as_str()?.parse().ok()?;
+    let last_seen = now.checked_sub(Duration::from_millis(field("age_ms")?))?;
+    let peer = SwarmPeer {
+        addr,
+        last_seen,
+        transfer: Transfer {
+            uploaded: field("uploaded")?,
+            downloaded: field("downloaded")?,
+            left: field("left")?,
+        },
+    };
+    Some((peer_id, peer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_saved_entries_of_any_age() {
+        let peer = |peer_id: &str, age_ms: u64| {
+            json!({
+                "peer_id": peer_id,
+                "address": "127.0.0.1:9000",
+                "age_ms": age_ms,
+                "uploaded": 0,
+                "downloaded": 0,
+                "left": 5,
+            })
+        };
+        let departed = |peer_id: &str, age_ms: u64| json!({ "peer_id": peer_id, "age_ms": age_ms });
+        let (a, b) = ("aa".repeat(20), "bb".repeat(20));
+        let state = json!({
+            "completed": 1,
+            "peers": [peer(&a, 1000), peer(&b, u64::MAX)],
+            "departed": [departed(&a, 1000), departed(&b, u64::MAX)],
+        });
+
+        let now = SystemTime::now();
+        let swarm = load_swarm(InfoHash([0; 20]), &state, now);
+        let (a, b) = (PeerId::from_hex(&a).unwrap(), PeerId::from_hex(&b).unwrap());
+        let second_ago = now - Duration::from_secs(1);
+        assert_eq!(swarm.completed, 1);
+        assert_eq!(swarm.peers[&a].last_seen, second_ago);
+        assert_eq!(swarm.departed[&a], second_ago);
+        // the clock of some platforms cannot go back that far, in which case
+        // the entry is skipped
+        assert!(swarm
+            .peers
+            .get(&b)
+            .is_none_or(|peer| peer.last_seen < second_ago));
+        assert!(swarm
+            .departed
+            .get(&b)
+            .is_none_or(|departed| *departed < second_ago));
+    }
+}
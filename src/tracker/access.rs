@@ -0,0 +1,162 @@
+//! Which swarms a tracker serves and to whom, and what each user did.
+//!
+//! The allowlist is a text file of hex info hashes, one per line, where blank
+//! lines and lines starting with `#` are skipped. The users file is a JSON
+//! object mapping each user name to its passkey.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::peer::PeerId;
+use crate::requests::request::{Event, Transfer};
+use crate::torrent::InfoHash;
+
+pub(super) struct Access {
+    /// Swarms peers may announce to, any when `None`.
+    allowlist: Option<HashSet<InfoHash>>,
+    /// User names by passkey. Without users, no passkey is needed.
+    users: Option<HashMap<String, String>>,
+    activity: Mutex<BTreeMap<String, UserActivity>>,
+}
+
+/// What the peers of a user announced since the tracker started.
+#[derive(Default)]
+struct UserActivity {
+    announces: u64,
+    last_announce: Option<SystemTime>,
+    /// Summed over the sessions of its peers.
+    uploaded: u64,
+    downloaded: u64,
+    completed: u64,
+    /// What each peer of the user last announced, to count what it adds.
+    peers: HashMap<(InfoHash, PeerId), Transfer>,
+}
+
+impl Access {
+    pub(super) fn load(allowlist: Option<&Path>, users: Option<&Path>) -> Self {
+        let allowlist = allowlist.map(|path| {
+            let text = std::fs::read_to_string(path).expect("read allowlist error");
+            let allowlist: HashSet<InfoHash> = text
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| InfoHash::from_hex(line).expect("bad info hash in allowlist"))
+                .collect();
+            println!(
+                "Allowing {} torrents from {}",
+                allowlist.len(),
+                path.display()
+            );
+            allowlist
+        });
+        let users = users.map(|path| {
+            let text = std::fs::read(path).expect("read users file error");
+            let users: Value = serde_json::from_slice(&text).expect("bad users file");
+            let mut by_passkey = HashMap::new();
+            for (user, passkey) in users.as_object().expect("bad users file") {
+                let passkey = passkey.as_str().expect("bad passkey in users file");
+                assert!(!passkey.is_empty(), "empty passkey for user {}", user);
+                let previous = by_passkey.insert(passkey.to_string(), user.clone());
+                assert!(
+                    previous.is_none(),
+                    "passkey of {} shared by {}",
+                    user,
+                    previous.unwrap()
+                );
+            }
+            println!("Loaded {} users from {}", by_passkey.len(), path.display());
+            by_passkey
+        });
+        Access {
+            allowlist,
+            users,
+            activity: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The user a request for `info_hash` is made by, `None` when the tracker
+    /// has no users, or why the request is refused.
+    pub(super) fn check(
+        &self,
+        info_hash: InfoHash,
+        passkey: &str,
+    ) -> Result<Option<String>, &'static str> {
+        if let Some(allowlist) = &self.allowlist {
+            if !allowlist.contains(&info_hash) {
+                return Err("torrent not allowed");
+            }
+        }
+        self.check_user(passkey)
+    }
+
+    /// Same as `check`, for a request that names no swarm.
+    pub(super) fn check_user(&self, passkey: &str) -> Result<Option<String>, &'static str> {
+        match &self.users {
+            None => Ok(None),
+            Some(users) => match users.get(passkey) {
+                Some(user) => Ok(Some(user.clone())),
+                None => Err("bad passkey"),
+            },
+        }
+    }
+
+    pub(super) fn allows(&self, info_hash: &InfoHash) -> bool {
+        self.allowlist
+            .as_ref()
+            .is_none_or(|allowlist| allowlist.contains(info_hash))
+    }
+
+    pub(super) fn record(
+        &self,
+        user: &str,
+        info_hash: InfoHash,
+        peer_id: PeerId,
+        event: Event,
+        transfer: &Transfer,
+    ) {
+        let mut activity = self.activity.lock().unwrap();
+        let activity = activity.entry(user.to_string()).or_default();
+        activity.announces += 1;
+        activity.last_announce = Some(SystemTime::now());
+        if event == Event::Completed {
+            activity.completed += 1;
+        }
+        let last = activity
+            .peers
+            .insert((info_hash, peer_id), transfer.clone())
+            .unwrap_or_default();
+        // peers count from their start, a restarted one from zero again
+        activity.uploaded += transfer.uploaded.saturating_sub(last.uploaded);
+        activity.downloaded += transfer.downloaded.saturating_sub(last.downloaded);
+        if event == Event::Stopped {
+            activity.peers.remove(&(info_hash, peer_id));
+        }
+    }
+
+    pub(super) fn activity_json(&self) -> Value {
+        let activity = self.activity.lock().unwrap();
+        let users: Vec<Value> = activity
+            .iter()
+            .map(|(user, activity)| {
+                let last_announce = activity
+                    .last_announce
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|time| time.as_secs());
+                json!({
+                    "user": user,
+                    "announces": activity.announces,
+                    "last_announce": last_announce,
+                    "uploaded": activity.uploaded,
+                    "downloaded": activity.downloaded,
+                    "completed": activity.completed,
+                    "peers": activity.peers.len(),
+                })
+            })
+            .collect();
+        json!({ "users": users })
+    }
+}
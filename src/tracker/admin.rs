@@ -0,0 +1,232 @@
+//! JSON view of the swarms of a running tracker, with admin actions.
+//!
+//! - `GET /swarms` lists every swarm with its counts.
+//! - `GET /swarms/<info hash>` adds every peer, its last announce and stats.
+//! - `DELETE /swarms/<info hash>/peers/<peer id>` evicts a peer, refusing
+//!   its announces until it would have expired.
+//! - `DELETE /swarms/<info hash>` closes a swarm, refusing its announces.
+//! - `GET /users` lists what the peers of each user announced.
+//!
+//! Every request needs an `Authorization: Bearer <token>` header, as the
+//! swarms list the addresses of their peers.
+
+use std::collections::HashSet;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use super::access::Access;
+use super::events::EventLog;
+use super::{Swarm, Swarms};
+use crate::http;
+use crate::peer::PeerId;
+use crate::torrent::InfoHash;
+
+pub(super) struct Admin {
+    pub(super) swarms: Arc<Mutex<Swarms>>,
+    pub(super) closed_swarms: Arc<Mutex<HashSet<InfoHash>>>,
+    pub(super) access: Arc<Access>,
+    pub(super) events: Arc<EventLog>,
+    pub(super) token: Option<String>,
+}
+
+pub(super) fn serve(listener: TcpListener, admin: Admin) {
+    for mut stream in listener.incoming().filter_map(|x| x.ok()) {
+        stream.set_read_timeout(Some(Duration::from_secs(1))).ok();
+        if let Ok(request) = http::read_request(&mut stream) {
+            admin.handle(&mut stream, request);
+        }
+    }
+}
+
+impl Admin {
+    fn handle(&self, stream: &mut TcpStream, request: http::Request) {
+        if let Err((status, body)) = self.authorize(&request) {
+            http::send_json(stream, status, &body).ok();
+            return;
+        }
+        let (status, body) = match (request.method.as_str(), &request.segments()[..]) {
+            ("GET", ["swarms"]) => (200, self.list_swarms()),
+            ("GET", ["swarms", info_hash]) => match InfoHash::from_hex(info_hash) {
+                Some(info_hash) => self.show_swarm(info_hash),
+                None => error(400, "bad info hash"),
+            },
+            ("DELETE", ["swarms", info_hash]) => match InfoHash::from_hex(info_hash) {
+                Some(info_hash) => self.close_swarm(info_hash),
+                None => error(400, "bad info hash"),
+            },
+            ("DELETE", ["swarms", info_hash, "peers", peer_id]) => {
+                match (InfoHash::from_hex(info_hash), PeerId::from_hex(peer_id)) {
+                    (Some(info_hash), Some(peer_id)) => self.evict_peer(info_hash, peer_id),
+                    _ => error(400, "bad info hash or peer id"),
+                }
+            }
+            ("GET", ["users"]) => (200, self.access.activity_json()),
+            (_, ["swarms"])
+            | (_, ["swarms", _])
+            | (_, ["swarms", _, "peers", _])
+            | (_, ["users"]) => error(405, "method not allowed"),
+            _ => error(404, "not found"),
+        };
+        http::send_json(stream, status, &body).ok();
+    }
+
+    fn authorize(&self, request: &http::Request) -> Result<(), (u16, Value)> {
+        let token = match &self.token {
+            Some(token) => token,
+            None => return Err(error(403, "admin actions are disabled")),
+        };
+        let given = request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or("");
+        if constant_time_eq(given.as_bytes(), token.as_bytes()) {
+            Ok(())
+        } else {
+            Err(error(401, "bad admin token"))
+        }
+    }
+
+    fn list_swarms(&self) -> Value {
+        let swarms = self.swarms.lock().unwrap();
+        let mut list: Vec<Value> = swarms
+            .iter()
+            .map(|(info_hash, swarm)| swarm_summary(*info_hash, swarm))
+            .collect();
+        list.sort_by(|a, b| a["info_hash"].as_str().cmp(&b["info_hash"].as_str()));
+        let closed: Vec<String> = self
+            .closed_swarms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(InfoHash::to_string)
+            .collect();
+        json!({ "swarms": list, "closed": closed })
+    }
+
+    fn show_swarm(&self, info_hash: InfoHash) -> (u16, Value) {
+        let swarms = self.swarms.lock().unwrap();
+        let swarm = match swarms.get(&info_hash) {
+            Some(swarm) => swarm,
+            None => return error(404, "no such swarm"),
+        };
+        let now = SystemTime::now();
+        let peers: Vec<Value> = swarm
+            .peers
+            .iter()
+            .map(|(peer_id, peer)| {
+                json!({
+                    "peer_id": peer_id.to_string(),
+                    "address": peer.addr.to_string(),
+                    "last_seen": peer.last_seen.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+                    "age_ms": now.duration_since(peer.last_seen).unwrap_or_default().as_millis() as u64,
+                    "uploaded": peer.transfer.uploaded,
+                    "downloaded": peer.transfer.downloaded,
+                    "left": peer.transfer.left,
+                })
+            })
+            .collect();
+        let mut body = swarm_summary(info_hash, swarm);
+        body["peers"] = Value::from(peers);
+        (200, body)
+    }
+
+    fn close_swarm(&self, info_hash: InfoHash) -> (u16, Value) {
+        println!("Admin closed swarm {}", info_hash);
+        self.closed_swarms.lock().unwrap().insert(info_hash);
+        let removed = self.swarms.lock().unwrap().remove(&info_hash);
+        let peers = removed.map(|swarm| swarm.peers).unwrap_or_default();
+        for (peer_id, peer) in &peers {
+            let details = json!({ "address": peer.addr.to_string(), "reason": "closed" });
+            self.events.record("leave", info_hash, *peer_id, details);
+        }
+        let peers = peers.len();
+        (
+            200,
+            json!({ "closed": info_hash.to_string(), "dropped_peers": peers }),
+        )
+    }
+
+    fn evict_peer(&self, info_hash: InfoHash, peer_id: PeerId) -> (u16, Value) {
+        let mut swarms = self.swarms.lock().unwrap();
+        match swarms.get_mut(&info_hash).and_then(|swarm| {
+            let peer = swarm.depart(peer_id)?;
+            swarm.evicted.insert(peer_id, SystemTime::now());
+            Some(peer)
+        }) {
+            Some(peer) => {
+                println!(
+                    "Admin evicted {} at {} from swarm {}",
+                    peer_id, peer.addr, info_hash
+                );
+                let details = json!({ "address": peer.addr.to_string(), "reason": "evicted" });
+                self.events.record("leave", info_hash, peer_id, details);
+                (200, json!({ "evicted": peer_id.to_string() }))
+            }
+            None => error(404, "no such peer"),
+        }
+    }
+}
+
+fn swarm_summary(info_hash: InfoHash, swarm: &Swarm) -> Value {
+    let seeders = swarm
+        .peers
+        .values()
+        .filter(|peer| peer.transfer.left == 0)
+        .count();
+    json!({
+        "info_hash": info_hash.to_string(),
+        "peers": swarm.peers.len(),
+        "seeders": seeders,
+        "leechers": swarm.peers.len() - seeders,
+        "completed": swarm.completed,
+    })
+}
+
+fn error(status: u16, reason: &str) -> (u16, Value) {
+    (status, json!({ "error": reason }))
+}
+
+/// Compare tokens without leaking through timing how much of them matched.
+pub(super) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::announce::Announce;
+    use crate::requests::request::{Event, Transfer};
+    use crate::tracker::Tracker;
+
+    #[test]
+    fn evicted_peers_are_not_listed_again() {
+        let tracker = Tracker::new();
+        let (info_hash, peer_id) = (InfoHash([1; 20]), PeerId([2; 20]));
+        let announce = || {
+            tracker.announce(Announce {
+                listening_addr: "127.0.0.1:7000".parse().unwrap(),
+                info_hash,
+                peer_id,
+                event: Event::Started,
+                transfer: Transfer::default(),
+                passkey: String::new(),
+            })
+        };
+        let admin = Admin {
+            swarms: Arc::clone(&tracker.swarms),
+            closed_swarms: Arc::clone(&tracker.closed_swarms),
+            access: Arc::clone(&tracker.access),
+            events: Arc::clone(&tracker.events),
+            token: None,
+        };
+        announce().unwrap();
+
+        assert_eq!(admin.evict_peer(info_hash, peer_id).0, 200);
+        assert_eq!(announce(), Err("evicted"));
+        assert!(tracker.swarms.lock().unwrap()[&info_hash].peers.is_empty());
+        assert_eq!(admin.evict_peer(info_hash, peer_id).0, 404);
+    }
+}
@@ -0,0 +1,269 @@
+//! Deployments: a swarm expected to reach given peers, or a number of peers,
+//! such as a build artifact pushed to a fleet. The tracker records which
+//! peers completed it, and answers how far it went.
+//!
+//! A peer completes a deployment when it reports completing its download,
+//! which it does once it verified the whole file. A target peer also
+//! completes it by announcing it has nothing left, so that targets already
+//! holding the file count. Without targets, that would count the seeders.
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpStream};
+
+use super::Tracker;
+use crate::peer::PeerId;
+use crate::requests::request::{Event, Transfer};
+use crate::responses::{self, response};
+use crate::torrent::InfoHash;
+
+pub(super) struct Deployment {
+    /// Listening addresses of the peers to reach, none to count peers.
+    targets: Vec<SocketAddr>,
+    expected: usize,
+    /// By listening address, a restarted peer counting once.
+    completed: HashMap<SocketAddr, PeerId>,
+}
+
+impl Deployment {
+    fn record(&mut self, peer_id: PeerId, addr: SocketAddr, event: Event, transfer: &Transfer) {
+        let completed = if self.targets.is_empty() {
+            event == Event::Completed
+        } else {
+            self.targets.contains(&addr)
+                && (event == Event::Completed || (transfer.left == 0 && event != Event::Stopped))
+        };
+        if completed && self.completed.insert(addr, peer_id).is_none() {
+            println!(
+                "{} at {} completed its deployment, {} of {}",
+                peer_id,
+                addr,
+                self.completed.len(),
+                self.expected
+            );
+        }
+    }
+
+    /// Whether every target completed it, or the expected number of peers.
+    fn done(&self) -> bool {
+        if self.targets.is_empty() {
+            self.completed.len() >= self.expected
+        } else {
+            self.targets
+                .iter()
+                .all(|target| self.completed.contains_key(target))
+        }
+    }
+}
+
+pub(super) type Deployments = HashMap<InfoHash, Deployment>;
+
+impl Tracker {
+    /// Record an announce against the deployment of its swarm, if any.
+    pub(super) fn record_deployment(
+        &self,
+        info_hash: InfoHash,
+        peer_id: PeerId,
+        addr: SocketAddr,
+        event: Event,
+        transfer: &Transfer,
+    ) {
+        if let Some(deployment) = self.deployments.lock().unwrap().get_mut(&info_hash) {
+            deployment.record(peer_id, addr, event, transfer);
+        }
+    }
+
+    pub(super) fn handle_deploy_request(
+        &mut self,
+        stream: &mut TcpStream,
+        info_hash: InfoHash,
+        targets: Vec<SocketAddr>,
+        count: usize,
+        passkey: &str,
+        replace: bool,
+    ) {
+        if let Err(reason) = self.access.check(info_hash, passkey) {
+            println!("Refusing deployment of swarm {}: {}", info_hash, reason);
+            crate::send_message(stream, crate::get_bad_response()).ok();
+            return;
+        }
+        if targets.is_empty() && count == 0 {
+            println!(
+                "Refusing deployment of swarm {}: no target and no count",
+                info_hash
+            );
+            crate::send_message(stream, crate::get_bad_response()).ok();
+            return;
+        }
+        let expected = if targets.is_empty() {
+            count
+        } else {
+            targets.len()
+        };
+        println!(
+            "Deploying swarm {} to {} peers, as asked by {}",
+            info_hash,
+            expected,
+            stream.peer_addr().unwrap()
+        );
+        let mut deployment = Deployment {
+            targets,
+            expected,
+            completed: HashMap::new(),
+        };
+        // targets may hold the file already
+        if let Some(swarm) = self.swarms.lock().unwrap().get(&info_hash) {
+            for (peer_id, peer) in &swarm.peers {
+                deployment.record(*peer_id, peer.addr, Event::None, &peer.transfer);
+            }
+        }
+        {
+            let mut deployments = self.deployments.lock().unwrap();
+            let running = deployments
+                .get(&info_hash)
+                .is_some_and(|running| !running.done());
+            if running && !replace {
+                println!(
+                    "Refusing deployment of swarm {}: the previous one is not done",
+                    info_hash
+                );
+                drop(deployments);
+                crate::send_message(stream, crate::get_bad_response()).ok();
+                return;
+            }
+            deployments.insert(info_hash, deployment);
+        }
+        let response = self.get_deployment_response(info_hash);
+        crate::send_message(stream, response).ok();
+    }
+
+    pub(super) fn handle_deployment_status_request(
+        &mut self,
+        stream: &mut TcpStream,
+        info_hash: InfoHash,
+        passkey: &str,
+    ) {
+        let response = match self.access.check(info_hash, passkey) {
+            Ok(_) => self.get_deployment_response(info_hash),
+            Err(_) => crate::get_bad_response(),
+        };
+        crate::send_message(stream, response).ok();
+    }
+
+    fn get_deployment_response(&self, info_hash: InfoHash) -> responses::Response {
+        let deployments = self.deployments.lock().unwrap();
+        let deployment = match deployments.get(&info_hash) {
+            Some(deployment) => deployment,
+            None => return crate::get_bad_response(),
+        };
+        let completed = deployment
+            .completed
+            .iter()
+            .map(|(addr, peer_id)| response::PeerAddress {
+                peer_id: peer_id.0.to_vec(),
+                address: addr.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        let stragglers: Vec<String> = if deployment.targets.is_empty() {
+            self.swarms
+                .lock()
+                .unwrap()
+                .get(&info_hash)
+                .map(|swarm| {
+                    swarm
+                        .peers
+                        .iter()
+                        .filter(|(_, peer)| {
+                            peer.transfer.left > 0 && !deployment.completed.contains_key(&peer.addr)
+                        })
+                        .map(|(_, peer)| peer.addr.to_string())
+                        .collect()
+                })
+                .unwrap_or_default()
+        } else {
+            deployment
+                .targets
+                .iter()
+                .filter(|target| !deployment.completed.contains_key(target))
+                .map(SocketAddr::to_string)
+                .collect()
+        };
+        let done = deployment.done();
+
+        let mut response = responses::Response::default();
+        response.r#type = Some(response::Type::Deployment(response::Deployment {
+            expected: deployment.expected as u32,
+            completed,
+            stragglers,
+            done,
+        }));
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deployment(targets: &[&str], expected: usize) -> Deployment {
+        let targets: Vec<SocketAddr> = targets.iter().map(|t| t.parse().unwrap()).collect();
+        Deployment {
+            expected: if targets.is_empty() {
+                expected
+            } else {
+                targets.len()
+            },
+            targets,
+            completed: HashMap::new(),
+        }
+    }
+
+    fn left(left: u64) -> Transfer {
+        Transfer {
+            left,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn targets_complete_by_holding_the_file() {
+        let mut deployment = deployment(&["10.0.0.1:7000", "10.0.0.2:7000"], 0);
+        let (first, second) = (
+            "10.0.0.1:7000".parse().unwrap(),
+            "10.0.0.2:7000".parse().unwrap(),
+        );
+        deployment.record(PeerId([1; 20]), first, Event::None, &left(10));
+        assert!(deployment.completed.is_empty());
+        deployment.record(PeerId([1; 20]), first, Event::None, &left(0));
+        // leaving with the file does not count as getting it
+        deployment.record(PeerId([2; 20]), second, Event::Stopped, &left(0));
+        assert_eq!(deployment.completed.len(), 1);
+        assert!(!deployment.done());
+
+        // a peer outside the targets does not count
+        let other = "10.0.0.3:7000".parse().unwrap();
+        deployment.record(PeerId([3; 20]), other, Event::Completed, &left(0));
+        assert!(!deployment.done());
+
+        deployment.record(PeerId([2; 20]), second, Event::Completed, &left(0));
+        assert!(deployment.done());
+    }
+
+    #[test]
+    fn counts_peers_completing_their_download() {
+        let mut deployment = deployment(&[], 2);
+        let first = "10.0.0.1:7000".parse().unwrap();
+        // seeders announcing nothing left are not counted
+        deployment.record(PeerId([1; 20]), first, Event::None, &left(0));
+        deployment.record(PeerId([1; 20]), first, Event::Completed, &left(0));
+        // a restarted peer counts once, by its listening address
+        deployment.record(PeerId([2; 20]), first, Event::Completed, &left(0));
+        assert_eq!(deployment.completed.len(), 1);
+        assert_eq!(deployment.completed[&first], PeerId([2; 20]));
+        assert!(!deployment.done());
+
+        let second = "10.0.0.2:7000".parse().unwrap();
+        deployment.record(PeerId([3; 20]), second, Event::Completed, &left(0));
+        assert!(deployment.done());
+    }
+}
@@ -0,0 +1,201 @@
+//! Append-only log of what happens to the swarms, one JSON object per line,
+//! for post-mortems and capacity planning.
+//!
+//! Every event has `time_ms`, the Unix time in milliseconds, `event`, `swarm`
+//! and `peer`, the hex info hash and peer id, and the fields of its kind.
+//! Once the log reaches its maximum size it is renamed with a `.1` suffix,
+//! older logs shifting to `.2` and so on, and the oldest ones beyond the
+//! count kept are deleted.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::peer::PeerId;
+use crate::torrent::InfoHash;
+
+pub(super) struct EventLog {
+    file: Option<Mutex<LogFile>>,
+}
+
+struct LogFile {
+    path: PathBuf,
+    max_bytes: u64,
+    /// Rotated logs kept besides the current one.
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl EventLog {
+    /// A log that records nothing.
+    pub(super) fn disabled() -> Self {
+        EventLog { file: None }
+    }
+
+    pub(super) fn open(path: &Path, max_bytes: u64, keep: usize) -> Self {
+        let file = open_append(path).expect("open event log error");
+        let size = file.metadata().expect("event log metadata error").len();
+        println!("Logging events to {}", path.display());
+        EventLog {
+            file: Some(Mutex::new(LogFile {
+                path: path.to_path_buf(),
+                max_bytes,
+                keep,
+                file,
+                size,
+            })),
+        }
+    }
+
+    /// Append an event, `details` being an object of the fields of its kind.
+    pub(super) fn record(&self, event: &str, info_hash: InfoHash, peer_id: PeerId, details: Value) {
+        let log = match &self.file {
+            Some(log) => log,
+            None => return,
+        };
+        let time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut line = json!({
+            "time_ms": time_ms,
+            "event": event,
+            "swarm": info_hash.to_string(),
+            "peer": peer_id.to_string(),
+        });
+        if let (Some(line), Value::Object(details)) = (line.as_object_mut(), details) {
+            line.extend(details);
+        }
+        let mut line = line.to_string();
+        line.push('\n');
+
+        let mut log = log.lock().unwrap();
+        if let Err(e) = log.append(line.as_bytes()) {
+            println!("Cannot write event log {}: {}", log.path.display(), e);
+        }
+    }
+}
+
+impl LogFile {
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            std::fs::remove_file(rotated(self.keep)).ok();
+            for n in (1..self.keep).rev() {
+                std::fs::rename(rotated(n), rotated(n + 1)).ok();
+            }
+            std::fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for each test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("events-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn lines(path: &Path) -> Vec<Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn record(log: &EventLog, n: u64) {
+        log.record(
+            "join",
+            InfoHash([1; 20]),
+            PeerId([2; 20]),
+            json!({ "n": n }),
+        );
+    }
+
+    #[test]
+    fn records_one_object_per_line() {
+        let dir = scratch_dir("lines");
+        let path = dir.join("events.log");
+        let log = EventLog::open(&path, 1 << 20, 2);
+        record(&log, 1);
+        record(&log, 2);
+        let lines = lines(&path);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "join");
+        assert_eq!(lines[0]["swarm"], InfoHash([1; 20]).to_string());
+        assert_eq!(lines[0]["peer"], PeerId([2; 20]).to_string());
+        assert_eq!(lines[1]["n"], 2);
+        assert!(lines[0]["time_ms"].as_u64().unwrap() > 0);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn rotates_keeping_the_newest_logs() {
+        let dir = scratch_dir("rotation");
+        let path = dir.join("events.log");
+        // every line is over half the maximum, so each goes to a new file
+        let log = EventLog::open(&path, 150, 2);
+        for n in 1..=4 {
+            record(&log, n);
+        }
+        let rotated = |n| dir.join(format!("events.log.{}", n));
+        assert_eq!(lines(&path)[0]["n"], 4);
+        assert_eq!(lines(&rotated(1))[0]["n"], 3);
+        assert_eq!(lines(&rotated(2))[0]["n"], 2);
+        assert!(!rotated(3).exists());
+
+        // a reopened log keeps counting its size
+        drop(log);
+        let log = EventLog::open(&path, 150, 2);
+        record(&log, 5);
+        assert_eq!(lines(&path)[0]["n"], 5);
+        assert_eq!(lines(&rotated(2))[0]["n"], 3);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn rotates_without_keeping_any() {
+        let dir = scratch_dir("no-keep");
+        let path = dir.join("events.log");
+        let log = EventLog::open(&path, 150, 0);
+        record(&log, 1);
+        record(&log, 2);
+        assert_eq!(lines(&path).len(), 1);
+        assert_eq!(lines(&path)[0]["n"], 2);
+        assert!(!dir.join("events.log.1").exists());
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
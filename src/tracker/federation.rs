@@ -0,0 +1,320 @@
+//! Federated trackers. Every second each tracker sends each of the others the
+//! peers it heard from and the peers that left since it last reached that
+//! one, with how long ago, and everything it lists the first time. Each one
+//! merges what it is sent into its own swarms, so that any of them answers for
+//! the peers announced to all of them, and takes over the peers of one that
+//! goes down. Listed peers announce within an interval, so a tracker coming
+//! back learns them all again soon.
+//!
+//! Ages rather than times are sent, so that the clocks of the trackers need
+//! not agree. A peer is only ever listed as of its latest announce, wherever
+//! it was made, and expires as usual once it stops announcing. With
+//! `verify_peers`, a tracker connects back to a gossiped peer before listing
+//! it, as it does with a peer announcing to it.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use serde_json::json;
+
+use super::admin::constant_time_eq;
+use super::{Candidate, Swarms, Tracker};
+use crate::announce;
+use crate::peer::PeerId;
+use crate::requests::request::{self, gossip, Gossip};
+use crate::requests::Request;
+use crate::responses::response;
+use crate::torrent::InfoHash;
+
+const GOSSIP_INTERVAL: Duration = Duration::from_secs(1);
+
+pub(super) fn gossip_loop(
+    swarms: Arc<Mutex<Swarms>>,
+    peer_trackers: Vec<SocketAddr>,
+    secret: String,
+) {
+    // when each peer tracker was last reached, none for one never reached
+    // or unreachable since
+    let mut reached: HashMap<SocketAddr, Option<SystemTime>> = peer_trackers
+        .iter()
+        .map(|&peer_tracker| (peer_tracker, None))
+        .collect();
+    let mut unreachable = HashSet::new();
+    loop {
+        std::thread::sleep(GOSSIP_INTERVAL);
+        for &peer_tracker in &peer_trackers {
+            let now = SystemTime::now();
+            let gossip = gossip_of(&swarms.lock().unwrap(), reached[&peer_tracker], &secret);
+            match send_gossip(peer_tracker, gossip) {
+                Ok(()) => {
+                    if unreachable.remove(&peer_tracker) {
+                        println!("Peer tracker {} is back", peer_tracker);
+                    }
+                    reached.insert(peer_tracker, Some(now));
+                }
+                Err(e) => {
+                    if unreachable.insert(peer_tracker) {
+                        println!("Peer tracker {} unreachable: {}", peer_tracker, e);
+                    }
+                    // what it was sent before may be lost with it
+                    reached.insert(peer_tracker, None);
+                }
+            }
+        }
+    }
+}
+
+/// What changed in the swarms since `since`, or all of them without it.
+fn gossip_of(swarms: &Swarms, since: Option<SystemTime>, secret: &str) -> Gossip {
+    let age_ms = |time: SystemTime| time.elapsed().unwrap_or_default().as_millis() as u64;
+    let changed = |time: SystemTime| since.is_none_or(|since| time >= since);
+    let swarms = swarms
+        .iter()
+        .map(|(info_hash, swarm)| gossip::Swarm {
+            info_hash: info_hash.0.to_vec(),
+            peers: swarm
+                .peers
+                .iter()
+                .filter(|(_, peer)| changed(peer.last_seen))
+                .map(|(peer_id, peer)| gossip::Peer {
+                    peer_id: peer_id.0.to_vec(),
+                    address: peer.addr.to_string(),
+                    age_ms: age_ms(peer.last_seen),
+                    transfer: Some(peer.transfer.clone()),
+                })
+                .collect(),
+            departed: swarm
+                .departed
+                .iter()
+                .filter(|(_, departed)| changed(**departed))
+                .map(|(peer_id, departed)| gossip::Peer {
+                    peer_id: peer_id.0.to_vec(),
+                    age_ms: age_ms(*departed),
+                    ..Default::default()
+                })
+                .collect(),
+        })
+        .filter(|swarm| !swarm.peers.is_empty() || !swarm.departed.is_empty())
+        .collect();
+    Gossip {
+        swarms,
+        secret: secret.to_string(),
+    }
+}
+
+fn send_gossip(peer_tracker: SocketAddr, gossip: Gossip) -> std::io::Result<()> {
+    let mut stream = announce::connect_tracker(peer_tracker)?;
+    let mut request = Request::default();
+    request.r#type = Some(request::Type::Gossip(gossip));
+    crate::send_message(&mut stream, request)?;
+    match crate::read_response(&mut stream)?.r#type {
+        Some(response::Type::Ok(_)) => Ok(()),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "gossip refused",
+        )),
+    }
+}
+
+impl Tracker {
+    /// Merge the swarms of another tracker into ours. Only the trackers given
+    /// as peers are listened to, with the secret of the federation if it has
+    /// one, anyone else could list any host.
+    pub(super) fn handle_gossip_request(&mut self, stream: &mut TcpStream, gossip: Gossip) {
+        let source = stream.peer_addr().unwrap();
+        if !self
+            .config
+            .peer_trackers
+            .iter()
+            .any(|peer_tracker| peer_tracker.ip() == source.ip())
+        {
+            println!("Refusing gossip from {}, not a peer tracker", source);
+            crate::send_message(stream, crate::get_bad_response()).ok();
+            return;
+        }
+        let secret = self.config.federation_secret.as_deref().unwrap_or("");
+        if !constant_time_eq(gossip.secret.as_bytes(), secret.as_bytes()) {
+            println!("Refusing gossip from {}, bad federation secret", source);
+            crate::send_message(stream, crate::get_bad_response()).ok();
+            return;
+        }
+
+        let now = SystemTime::now();
+        let closed_swarms = self.closed_swarms.lock().unwrap().clone();
+        let mut swarms = self.swarms.lock().unwrap();
+        let mut learned = 0;
+        for gossiped in gossip.swarms {
+            let info_hash = match InfoHash::from_bytes(&gossiped.info_hash) {
+                Some(info_hash)
+                    if !closed_swarms.contains(&info_hash) && self.access.allows(&info_hash) =>
+                {
+                    info_hash
+                }
+                _ => continue,
+            };
+            let swarm = swarms.entry(info_hash).or_default();
+            for departed in gossiped.departed {
+                let peer_id = match PeerId::from_bytes(&departed.peer_id) {
+                    Some(peer_id) => peer_id,
+                    None => continue,
+                };
+                // peers that old are not listed anyway, and the age comes
+                // from another host
+                let age = Duration::from_millis(departed.age_ms);
+                if age >= self.config.expire {
+                    continue;
+                }
+                let departed = now - age;
+                if swarm
+                    .departed
+                    .get(&peer_id)
+                    .is_some_and(|known| *known >= departed)
+                {
+                    continue;
+                }
+                swarm.departed.insert(peer_id, departed);
+                if swarm
+                    .peers
+                    .get(&peer_id)
+                    .is_some_and(|peer| peer.last_seen <= departed)
+                {
+                    println!("{} left swarm {}, told by {}", peer_id, info_hash, source);
+                    if let Some(peer) = swarm.peers.remove(&peer_id) {
+                        let details = json!({
+                            "address": peer.addr.to_string(),
+                            "reason": "gossip",
+                            "tracker": source.to_string(),
+                        });
+                        self.events.record("leave", info_hash, peer_id, details);
+                    }
+                }
+            }
+            for peer in gossiped.peers {
+                let (peer_id, addr) = match (
+                    PeerId::from_bytes(&peer.peer_id),
+                    peer.address.parse::<SocketAddr>(),
+                ) {
+                    (Some(peer_id), Ok(addr)) => (peer_id, addr),
+                    _ => continue,
+                };
+                let age = Duration::from_millis(peer.age_ms);
+                if age >= self.config.expire {
+                    continue;
+                }
+                let last_seen = now - age;
+                let newer = swarm
+                    .peers
+                    .get(&peer_id)
+                    .is_none_or(|known| known.last_seen < last_seen);
+                let departed = swarm
+                    .departed
+                    .get(&peer_id)
+                    .is_some_and(|departed| *departed >= last_seen);
+                let evicted = swarm.evicted.contains_key(&peer_id);
+                if newer && !departed && !evicted {
+                    let transfer = peer.transfer.unwrap_or_default();
+                    if self.config.verify_peers && !swarm.peers.contains_key(&peer_id) {
+                        let candidate = Candidate {
+                            info_hash,
+                            peer_id,
+                            addr,
+                            transfer,
+                            last_seen,
+                        };
+                        self.verify_later(swarm, candidate);
+                        continue;
+                    }
+                    if !swarm.peers.contains_key(&peer_id) {
+                        learned += 1;
+                        let details = json!({
+                            "address": addr.to_string(),
+                            "left": transfer.left,
+                            "tracker": source.to_string(),
+                        });
+                        self.events.record("join", info_hash, peer_id, details);
+                    }
+                    swarm.insert_seen(peer_id, addr, transfer, last_seen);
+                }
+            }
+        }
+        drop(swarms);
+        if learned > 0 {
+            println!("Learned {} peers from tracker {}", learned, source);
+        }
+        crate::send_message(stream, crate::get_ok_response()).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::TrackerConfig;
+    use std::net::TcpListener;
+
+    fn tracker() -> Tracker {
+        Tracker::with_config(TrackerConfig {
+            peer_trackers: vec!["127.0.0.1:1".parse().unwrap()],
+            ..Default::default()
+        })
+    }
+
+    /// Hand `gossip` to the tracker as a peer tracker would, telling whether
+    /// it was accepted.
+    fn gossip(tracker: &mut Tracker, swarms: Vec<gossip::Swarm>) -> bool {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stream = listener.accept().unwrap().0;
+        let gossip = Gossip {
+            swarms,
+            secret: String::new(),
+        };
+        tracker.handle_gossip_request(&mut stream, gossip);
+        matches!(
+            crate::read_response(&mut client).unwrap().r#type,
+            Some(response::Type::Ok(_))
+        )
+    }
+
+    fn peer(peer_id: u8, age_ms: u64) -> gossip::Peer {
+        gossip::Peer {
+            peer_id: vec![peer_id; 20],
+            address: "10.0.0.1:7000".to_string(),
+            age_ms,
+            transfer: None,
+        }
+    }
+
+    #[test]
+    fn merges_peers_and_departures() {
+        let mut tracker = tracker();
+        let swarm = gossip::Swarm {
+            info_hash: vec![1; 20],
+            peers: vec![peer(2, 0), peer(3, 100), peer(4, u64::MAX)],
+            departed: vec![peer(3, 0)],
+        };
+        assert!(gossip(&mut tracker, vec![swarm]));
+        let swarms = tracker.swarms.lock().unwrap();
+        let swarm = &swarms[&InfoHash([1; 20])];
+        // one left after it was seen, one is too old to list
+        let mut listed: Vec<_> = swarm.peers.keys().cloned().collect();
+        listed.sort_by_key(|peer_id| peer_id.0);
+        assert_eq!(listed, [PeerId([2; 20])]);
+    }
+
+    #[test]
+    fn ignores_departures_of_any_age() {
+        let mut tracker = tracker();
+        let swarm = gossip::Swarm {
+            info_hash: vec![1; 20],
+            peers: vec![peer(2, 0)],
+            departed: vec![peer(3, u64::MAX), peer(2, u64::MAX)],
+        };
+        assert!(gossip(&mut tracker, vec![swarm]));
+        let swarms = tracker.swarms.lock().unwrap();
+        let swarm = &swarms[&InfoHash([1; 20])];
+        assert!(swarm.peers.contains_key(&PeerId([2; 20])));
+        assert!(swarm.departed.is_empty());
+    }
+}
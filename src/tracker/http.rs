@@ -0,0 +1,299 @@
+//! The tracker side of the HTTP tracker protocol of BitTorrent (BEP 3), with
+//! the compact peer lists of BEP 23 and the scrape convention.
+//!
+//! - `GET /announce` records an announce and answers a sample of the swarm.
+//! - `GET /scrape` answers the counts of the swarms named by `info_hash`, or of
+//!   every swarm.
+//!
+//! Answers are bencoded. Errors are answered as a `failure reason`, which
+//! clients only read from a 200 response.
+
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+use super::Tracker;
+use crate::announce::Announce;
+use crate::bencode::{self, Value};
+use crate::http;
+use crate::locality::Proximity;
+use crate::peer::PeerId;
+use crate::requests::request::{Event, Transfer};
+use crate::torrent::InfoHash;
+use crate::udp_tracker::encode_compact_peer;
+
+pub(super) fn serve(listener: TcpListener, tracker: Tracker) {
+    for mut stream in listener.incoming().filter_map(|x| x.ok()) {
+        let connection = match tracker.open_connection(&stream) {
+            Some(connection) => connection,
+            None => continue,
+        };
+        stream.set_read_timeout(Some(Duration::from_secs(1))).ok();
+        let thread_pool = tracker.thread_pool.clone();
+        let tracker = tracker.clone();
+        thread_pool.execute(move || {
+            if let Ok(request) = http::read_request(&mut stream) {
+                tracker.handle_http_request(&mut stream, request);
+            }
+            drop(connection);
+        });
+    }
+}
+
+impl Tracker {
+    fn handle_http_request(&self, stream: &mut TcpStream, request: http::Request) {
+        let source = match stream.peer_addr() {
+            Ok(source) => source,
+            Err(_) => return,
+        };
+        let answer = match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/announce") => {
+                self.metrics.requests.inc("http_announce");
+                self.handle_http_announce(&request, source)
+            }
+            ("GET", "/scrape") => {
+                self.metrics.requests.inc("http_scrape");
+                self.handle_http_scrape(&request)
+            }
+            _ => {
+                http::send_response(stream, 404, "text/plain", b"not found\n").ok();
+                return;
+            }
+        };
+        let answer = answer.unwrap_or_else(|reason| {
+            println!("Rejecting HTTP request from {}: {}", source, reason);
+            dict(vec![("failure reason", Value::Bytes(reason.into()))])
+        });
+        http::send_response(stream, 200, "text/plain", &bencode::encode(&answer)).ok();
+    }
+
+    fn handle_http_announce(
+        &self,
+        request: &http::Request,
+        source: SocketAddr,
+    ) -> Result<Value, &'static str> {
+        let info_hash = request
+            .param("info_hash")
+            .and_then(InfoHash::from_bytes)
+            .ok_or("bad info_hash")?;
+        let peer_id = request
+            .param("peer_id")
+            .and_then(PeerId::from_bytes)
+            .ok_or("bad peer_id")?;
+        let number = |key| -> Result<u64, &'static str> {
+            match request.param_str(key) {
+                Some(value) => value.parse().map_err(|_| "bad number"),
+                None => Ok(0),
+            }
+        };
+        let port: u16 = request
+            .param_str("port")
+            .and_then(|port| port.parse().ok())
+            .ok_or("bad port")?;
+        let transfer = Transfer {
+            uploaded: number("uploaded")?,
+            downloaded: number("downloaded")?,
+            left: number("left")?,
+        };
+        let event = match request.param_str("event").unwrap_or("") {
+            "" | "empty" => Event::None,
+            "started" => Event::Started,
+            "completed" => Event::Completed,
+            "stopped" => Event::Stopped,
+            _ => return Err("bad event"),
+        };
+        let numwant = number("numwant")? as usize;
+        // as over TCP, the source IP unless the client is trusted to give its own
+        let ip = match request.param_str("ip") {
+            Some(ip) if self.config.trust_client_addr => ip.parse().map_err(|_| "bad ip")?,
+            _ => source.ip(),
+        };
+        let client_addr = SocketAddr::new(ip, port);
+        println!(
+            "Handling HTTP announce from {} for swarm {}, peer {} listening at {}",
+            source, info_hash, peer_id, client_addr
+        );
+        let passkey = request.param_str("passkey").unwrap_or("");
+        self.announce(Announce {
+            listening_addr: client_addr,
+            info_hash,
+            peer_id,
+            event,
+            transfer,
+            passkey: passkey.to_string(),
+        })?;
+
+        let peers = if event == Event::Stopped {
+            vec![]
+        } else {
+            self.sample_peers(info_hash, Some(peer_id), numwant, source.ip(), |_| true)
+        };
+        let peers = if request.param_str("compact") == Some("0") {
+            let peers = peers
+                .into_iter()
+                .map(|(peer_id, addr, _)| {
+                    dict(vec![
+                        ("peer id", Value::Bytes(peer_id.0.to_vec())),
+                        ("ip", Value::Bytes(addr.ip().to_string().into_bytes())),
+                        ("port", Value::Integer(addr.port().into())),
+                    ])
+                })
+                .collect();
+            vec![("peers", Value::List(peers))]
+        } else {
+            // IPv4 peers in `peers` and IPv6 ones in `peers6`, 6 and 18 bytes each
+            let (peers, peers6): (Vec<_>, Vec<_>) =
+                peers.into_iter().partition(|(_, addr, _)| addr.is_ipv4());
+            let compact = |peers: Vec<(PeerId, SocketAddr, Proximity)>| {
+                Value::Bytes(
+                    peers
+                        .into_iter()
+                        .flat_map(|(_, addr, _)| encode_compact_peer(addr))
+                        .collect(),
+                )
+            };
+            vec![("peers", compact(peers)), ("peers6", compact(peers6))]
+        };
+
+        let stats = self.swarm_stats(vec![info_hash]).remove(0);
+        let mut answer = vec![
+            ("interval", seconds(self.config.interval)),
+            ("min interval", seconds(self.config.min_interval)),
+            ("complete", Value::Integer(stats.seeders.into())),
+            ("incomplete", Value::Integer(stats.leechers.into())),
+        ];
+        answer.extend(peers);
+        Ok(dict(answer))
+    }
+
+    fn handle_http_scrape(&self, request: &http::Request) -> Result<Value, &'static str> {
+        let info_hashes: Option<Vec<InfoHash>> = request
+            .query
+            .iter()
+            .filter(|(key, _)| key == "info_hash")
+            .map(|(_, info_hash)| InfoHash::from_bytes(info_hash))
+            .collect();
+        let info_hashes = info_hashes.ok_or("bad info_hash")?;
+        let passkey = request.param_str("passkey").unwrap_or("");
+        let info_hashes = self.scrape_scope(info_hashes, passkey)?;
+        let files = self
+            .swarm_stats(info_hashes)
+            .into_iter()
+            .map(|stats| {
+                let counts = dict(vec![
+                    ("complete", Value::Integer(stats.seeders.into())),
+                    ("downloaded", Value::Integer(stats.completed as i64)),
+                    ("incomplete", Value::Integer(stats.leechers.into())),
+                ]);
+                (stats.info_hash, counts)
+            })
+            .collect();
+        Ok(dict(vec![("files", Value::Dict(files))]))
+    }
+}
+
+fn dict(entries: Vec<(&str, Value)>) -> Value {
+    let entries: BTreeMap<Vec<u8>, Value> = entries
+        .into_iter()
+        .map(|(key, value)| (key.as_bytes().to_vec(), value))
+        .collect();
+    Value::Dict(entries)
+}
+
+/// Whole seconds, as the protocol counts intervals, and at least one.
+fn seconds(interval: Duration) -> Value {
+    Value::Integer(interval.as_secs().max(1) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announce(info_hash: u8, peer_id: u8, port: u16, extra: &[(&str, &str)]) -> http::Request {
+        let mut query = vec![
+            ("info_hash".to_string(), vec![info_hash; 20]),
+            ("peer_id".to_string(), vec![peer_id; 20]),
+            ("port".to_string(), port.to_string().into_bytes()),
+            ("left".to_string(), b"100".to_vec()),
+        ];
+        for (key, value) in extra {
+            query.push((key.to_string(), value.as_bytes().to_vec()));
+        }
+        http::Request {
+            method: "GET".to_string(),
+            path: "/announce".to_string(),
+            query,
+            headers: vec![],
+        }
+    }
+
+    #[test]
+    fn announce_lists_the_other_peers() {
+        let tracker = Tracker::new();
+        let source: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let first = announce(1, 2, 7000, &[("event", "started")]);
+        tracker.handle_http_announce(&first, source).unwrap();
+
+        let answer = tracker
+            .handle_http_announce(&announce(1, 3, 7001, &[]), source)
+            .unwrap();
+        assert_eq!(answer.get("incomplete"), Some(&Value::Integer(2)));
+        assert_eq!(answer.get("complete"), Some(&Value::Integer(0)));
+        assert_eq!(
+            answer.get("peers"),
+            Some(&Value::Bytes(vec![127, 0, 0, 1, 0x1b, 0x58]))
+        );
+        assert_eq!(answer.get("peers6"), Some(&Value::Bytes(vec![])));
+
+        let answer = tracker
+            .handle_http_announce(&announce(1, 3, 7001, &[("compact", "0")]), source)
+            .unwrap();
+        let peer = &answer.get("peers").unwrap().as_list().unwrap()[0];
+        assert_eq!(peer.get("peer id").unwrap().as_bytes(), Some(&[2; 20][..]));
+        assert_eq!(peer.get("ip").unwrap().as_str(), Some("127.0.0.1"));
+        assert_eq!(peer.get("port"), Some(&Value::Integer(7000)));
+    }
+
+    #[test]
+    fn announce_refuses_bad_parameters() {
+        let tracker = Tracker::new();
+        let source: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let refuse = |request: &http::Request, reason| {
+            assert_eq!(
+                tracker.handle_http_announce(request, source).unwrap_err(),
+                reason
+            );
+            // nothing is recorded of a refused announce
+            let swarms = tracker.swarms.lock().unwrap();
+            assert!(swarms
+                .get(&InfoHash([1; 20]))
+                .is_none_or(|swarm| swarm.peers.is_empty()));
+        };
+        let mut request = announce(1, 2, 7000, &[]);
+        request.query[0].1.pop();
+        refuse(&request, "bad info_hash");
+        refuse(&announce(1, 2, 7000, &[("event", "paused")]), "bad event");
+        refuse(&announce(1, 2, 7000, &[("numwant", "-1")]), "bad number");
+        refuse(
+            &announce(1, 2, 7000, &[("event", "started"), ("numwant", "many")]),
+            "bad number",
+        );
+    }
+
+    #[test]
+    fn scrape_counts_the_swarm() {
+        let tracker = Tracker::new();
+        let source: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let request = announce(1, 2, 7000, &[("event", "completed")]);
+        tracker.handle_http_announce(&request, source).unwrap();
+
+        let mut scrape = announce(1, 2, 7000, &[]);
+        scrape.path = "/scrape".to_string();
+        scrape.query.truncate(1);
+        let answer = tracker.handle_http_scrape(&scrape).unwrap();
+        let files = answer.get("files").unwrap().as_dict().unwrap();
+        let counts = &files[&vec![1; 20]];
+        assert_eq!(counts.get("downloaded"), Some(&Value::Integer(1)));
+        assert_eq!(counts.get("incomplete"), Some(&Value::Integer(1)));
+    }
+}
@@ -0,0 +1,307 @@
+//! Per-source limits, so that no single client can monopolize the tracker.
+//!
+//! Every source IP gets a bucket of requests, refilled at the allowed rate up
+//! to one second worth of them. A source going over it is banned for a while:
+//! its connections are closed and its packets dropped unanswered.
+//!
+//! A source may also hold a few connections at once, and apart from them a
+//! number of subscriptions, which stay open as long as their peers run. A
+//! connection over either count is closed, without banning the source, as
+//! many peers may share its address.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const FORGET_INTERVAL: Duration = Duration::from_secs(10);
+
+pub(super) struct Limits {
+    requests_per_sec: f64,
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    max_subscriptions_per_ip: usize,
+    ban: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    sources: HashMap<IpAddr, Source>,
+    /// Connections open, of every source, subscriptions aside.
+    connections: usize,
+}
+
+struct Source {
+    /// Requests the source may still make right away.
+    tokens: f64,
+    refilled: Instant,
+    connections: usize,
+    subscriptions: usize,
+    banned_until: Option<Instant>,
+}
+
+/// A connection counted against the limits until it is dropped.
+pub(super) struct Connection {
+    limits: Arc<Limits>,
+    ip: IpAddr,
+    /// Counted as a subscription rather than as a connection.
+    subscription: bool,
+}
+
+impl Limits {
+    pub(super) fn new(
+        requests_per_sec: f64,
+        max_connections: usize,
+        max_connections_per_ip: usize,
+        max_subscriptions_per_ip: usize,
+        ban: Duration,
+    ) -> Self {
+        Limits {
+            requests_per_sec,
+            max_connections,
+            max_connections_per_ip,
+            max_subscriptions_per_ip,
+            ban,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Count a request of `ip`, or tell why it is refused.
+    pub(super) fn admit_request(&self, ip: IpAddr) -> Result<(), &'static str> {
+        let mut state = self.state.lock().unwrap();
+        let source = self.source(&mut state, ip)?;
+        self.take_token(source, ip)
+    }
+
+    /// Count a new connection of `ip` and its first request, or tell why it
+    /// is refused.
+    pub(super) fn open_connection(
+        self: &Arc<Self>,
+        ip: IpAddr,
+    ) -> Result<Connection, &'static str> {
+        let mut state = self.state.lock().unwrap();
+        // the tracker is busy rather than the source abusive
+        if state.connections >= self.max_connections {
+            return Err("busy");
+        }
+        let source = self.source(&mut state, ip)?;
+        if source.connections >= self.max_connections_per_ip {
+            return Err("too_many_connections");
+        }
+        self.take_token(source, ip)?;
+        source.connections += 1;
+        state.connections += 1;
+        Ok(Connection {
+            limits: Arc::clone(self),
+            ip,
+            subscription: false,
+        })
+    }
+
+    /// The source of `ip`, refilled, unless it is banned.
+    fn source<'a>(&self, state: &'a mut State, ip: IpAddr) -> Result<&'a mut Source, &'static str> {
+        let now = Instant::now();
+        let burst = self.requests_per_sec.max(1.0);
+        let source = state.sources.entry(ip).or_insert(Source {
+            tokens: burst,
+            refilled: now,
+            connections: 0,
+            subscriptions: 0,
+            banned_until: None,
+        });
+        if let Some(banned_until) = source.banned_until {
+            if now < banned_until {
+                return Err("banned");
+            }
+            println!("Lifting the ban of {}", ip);
+            source.banned_until = None;
+        }
+        let elapsed = now.duration_since(source.refilled).as_secs_f64();
+        source.tokens = (source.tokens + elapsed * self.requests_per_sec).min(burst);
+        source.refilled = now;
+        Ok(source)
+    }
+
+    fn take_token(&self, source: &mut Source, ip: IpAddr) -> Result<(), &'static str> {
+        if source.tokens < 1.0 {
+            self.ban(source, ip, "too many requests");
+            return Err("banned");
+        }
+        source.tokens -= 1.0;
+        Ok(())
+    }
+
+    fn ban(&self, source: &mut Source, ip: IpAddr, reason: &str) {
+        println!("Banning {} for {:?}: {}", ip, self.ban, reason);
+        source.banned_until = Some(Instant::now() + self.ban);
+    }
+
+    /// Forget the sources that are back to a full bucket, hold no connection
+    /// and are not banned.
+    fn forget_idle(&self) {
+        let now = Instant::now();
+        let burst = self.requests_per_sec.max(1.0);
+        let requests_per_sec = self.requests_per_sec;
+        self.state.lock().unwrap().sources.retain(|_, source| {
+            let elapsed = now.duration_since(source.refilled).as_secs_f64();
+            source.tokens + elapsed * requests_per_sec < burst
+                || source.connections > 0
+                || source.subscriptions > 0
+                || source.banned_until.is_some_and(|until| now < until)
+        });
+    }
+}
+
+impl Connection {
+    /// Count the connection as a subscription of its source from now on,
+    /// rather than as one of its connections, or tell why it is refused.
+    pub(super) fn into_subscription(mut self) -> Result<Connection, &'static str> {
+        let limits = Arc::clone(&self.limits);
+        let mut state = limits.state.lock().unwrap();
+        // the source is kept as long as it holds a connection
+        let source = state.sources.get_mut(&self.ip).unwrap();
+        if source.subscriptions >= limits.max_subscriptions_per_ip {
+            drop(state);
+            return Err("too_many_subscriptions");
+        }
+        source.connections -= 1;
+        source.subscriptions += 1;
+        state.connections -= 1;
+        self.subscription = true;
+        Ok(self)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut state = self.limits.state.lock().unwrap();
+        if !self.subscription {
+            state.connections -= 1;
+        }
+        if let Some(source) = state.sources.get_mut(&self.ip) {
+            if self.subscription {
+                source.subscriptions -= 1;
+            } else {
+                source.connections -= 1;
+            }
+        }
+    }
+}
+
+pub(super) fn forget_loop(limits: Arc<Limits>) {
+    loop {
+        std::thread::sleep(FORGET_INTERVAL);
+        limits.forget_idle();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BAN: Duration = Duration::from_millis(100);
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn bans_a_source_past_its_burst() {
+        let limits = Limits::new(20.0, 10, 10, 10, BAN);
+        for _ in 0..20 {
+            assert_eq!(limits.admit_request(ip(1)), Ok(()));
+        }
+        assert_eq!(limits.admit_request(ip(1)), Err("banned"));
+        // other sources have buckets of their own
+        assert_eq!(limits.admit_request(ip(2)), Ok(()));
+
+        std::thread::sleep(BAN);
+        // refilled meanwhile, at 20 per second
+        assert_eq!(limits.admit_request(ip(1)), Ok(()));
+    }
+
+    #[test]
+    fn refills_at_the_allowed_rate() {
+        let limits = Limits::new(10.0, 10, 10, 10, Duration::from_secs(60));
+        for _ in 0..10 {
+            limits.admit_request(ip(1)).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(150));
+        // a request and a half worth refilled, not the whole bucket
+        assert_eq!(limits.admit_request(ip(1)), Ok(()));
+        let admitted = (0..10)
+            .take_while(|_| limits.admit_request(ip(1)).is_ok())
+            .count();
+        assert!(admitted < 9);
+    }
+
+    #[test]
+    fn a_fractional_rate_still_allows_a_request() {
+        let limits = Limits::new(0.5, 10, 10, 10, BAN);
+        assert_eq!(limits.admit_request(ip(1)), Ok(()));
+        assert_eq!(limits.admit_request(ip(1)), Err("banned"));
+    }
+
+    #[test]
+    fn counts_connections_until_dropped() {
+        let limits = Arc::new(Limits::new(100.0, 3, 2, 2, BAN));
+        let first = limits.open_connection(ip(1)).unwrap();
+        let second = limits.open_connection(ip(1)).unwrap();
+        let other = limits.open_connection(ip(2)).unwrap();
+        // the tracker is full, which bans no one
+        assert_eq!(limits.open_connection(ip(3)).err(), Some("busy"));
+        drop(other);
+        assert!(limits.open_connection(ip(3)).is_ok());
+
+        // nor does a source holding too many
+        assert_eq!(
+            limits.open_connection(ip(1)).err(),
+            Some("too_many_connections")
+        );
+        drop(first);
+        assert!(limits.open_connection(ip(1)).is_ok());
+        drop(second);
+        assert_eq!(limits.state.lock().unwrap().connections, 0);
+    }
+
+    #[test]
+    fn counts_subscriptions_apart_from_connections() {
+        let limits = Arc::new(Limits::new(100.0, 3, 1, 2, BAN));
+        let subscribe = || {
+            limits
+                .open_connection(ip(1))
+                .and_then(Connection::into_subscription)
+        };
+        let first = subscribe().unwrap();
+        let _second = subscribe().unwrap();
+        // subscriptions leave room for requests, of the source and of others
+        let request = limits.open_connection(ip(1)).unwrap();
+        assert_eq!(limits.state.lock().unwrap().connections, 1);
+        drop(request);
+
+        assert_eq!(subscribe().err(), Some("too_many_subscriptions"));
+        assert_eq!(limits.state.lock().unwrap().sources[&ip(1)].connections, 0);
+        drop(first);
+        assert!(subscribe().is_ok());
+        assert_eq!(limits.admit_request(ip(1)), Ok(()));
+    }
+
+    #[test]
+    fn forgets_idle_sources_only() {
+        let limits = Arc::new(Limits::new(1000.0, 10, 10, 10, BAN));
+        let _connection = limits.open_connection(ip(1)).unwrap();
+        let _subscription = limits
+            .open_connection(ip(4))
+            .and_then(Connection::into_subscription)
+            .unwrap();
+        limits.admit_request(ip(2)).unwrap();
+        limits.admit_request(ip(3)).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        limits.forget_idle();
+        let state = limits.state.lock().unwrap();
+        let mut kept: Vec<_> = state.sources.keys().cloned().collect();
+        kept.sort();
+        assert_eq!(kept, [ip(1), ip(4)]);
+    }
+}
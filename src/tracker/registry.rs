@@ -0,0 +1,172 @@
+//! Torrent files uploaded to the tracker, so that peers can start from an
+//! info hash alone rather than a torrent file passed around by hand.
+//!
+//! Each one is kept as uploaded, signature included, under the info hash of
+//! its content. With a torrent directory, they are also saved there as
+//! `<info hash>.torrent` and loaded back when the tracker starts.
+//!
+//! Once stored, the torrent of a swarm is only replaced by one signed by the
+//! same publisher, or by an upload carrying the admin token.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::{admin, Tracker};
+use crate::requests::request::UploadTorrent;
+use crate::signing;
+use crate::torrent::{InfoHash, Torrent};
+
+pub(super) struct Registry {
+    dir: Option<PathBuf>,
+    torrents: Mutex<HashMap<InfoHash, Vec<u8>>>,
+}
+
+impl Registry {
+    pub(super) fn load(dir: Option<&Path>) -> Self {
+        let mut torrents = HashMap::new();
+        if let Some(dir) = dir {
+            std::fs::create_dir_all(dir).expect("create torrent directory error");
+            for entry in std::fs::read_dir(dir).expect("read torrent directory error") {
+                let path = entry.expect("read torrent directory error").path();
+                if path
+                    .extension()
+                    .is_none_or(|extension| extension != "torrent")
+                {
+                    continue;
+                }
+                let info_hash = path
+                    .file_stem()
+                    .and_then(|stem| InfoHash::from_hex(&stem.to_string_lossy()))
+                    .unwrap_or_else(|| panic!("bad torrent file name {}", path.display()));
+                let torrent = std::fs::read(&path).expect("read torrent file error");
+                torrents.insert(info_hash, torrent);
+            }
+            println!("Loaded {} torrents from {}", torrents.len(), dir.display());
+        }
+        Registry {
+            dir: dir.map(Path::to_path_buf),
+            torrents: Mutex::new(torrents),
+        }
+    }
+
+    /// Store a torrent, replacing the previous one of its swarm only when
+    /// `may_replace` accepts it.
+    fn insert(
+        &self,
+        info_hash: InfoHash,
+        torrent: Vec<u8>,
+        may_replace: impl FnOnce(&[u8], &[u8]) -> Result<(), String>,
+    ) -> Result<(), String> {
+        // held throughout, so two uploads cannot both pass the check
+        let mut torrents = self.torrents.lock().unwrap();
+        if let Some(stored) = torrents.get(&info_hash) {
+            may_replace(stored, &torrent)?;
+        }
+        if let Some(dir) = &self.dir {
+            self.save(dir, info_hash, &torrent)
+                .map_err(|e| format!("cannot store it: {}", e))?;
+        }
+        torrents.insert(info_hash, torrent);
+        Ok(())
+    }
+
+    fn save(&self, dir: &Path, info_hash: InfoHash, torrent: &[u8]) -> io::Result<()> {
+        // written aside and renamed, so a crash never leaves half a file
+        let path = dir.join(format!("{}.torrent", info_hash));
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, torrent)?;
+        std::fs::rename(&temporary, &path)
+    }
+
+    fn get(&self, info_hash: InfoHash) -> Option<Vec<u8>> {
+        self.torrents.lock().unwrap().get(&info_hash).cloned()
+    }
+}
+
+impl Tracker {
+    pub(super) fn handle_upload_torrent_request(
+        &mut self,
+        stream: &mut TcpStream,
+        upload: UploadTorrent,
+    ) {
+        let UploadTorrent {
+            torrent,
+            passkey,
+            admin_token,
+        } = upload;
+        let source = stream.peer_addr().unwrap();
+        let info_hash = match Torrent::parse(&torrent) {
+            Ok(parsed) => parsed.info_hash,
+            Err(e) => {
+                println!("Refusing torrent from {}: {}", source, e);
+                crate::send_message(stream, crate::get_bad_response()).ok();
+                return;
+            }
+        };
+        if let Err(reason) = self.access.check(info_hash, &passkey) {
+            println!("Refusing torrent of swarm {}: {}", info_hash, reason);
+            crate::send_message(stream, crate::get_bad_response()).ok();
+            return;
+        }
+        let may_replace =
+            |stored: &[u8], torrent: &[u8]| self.may_replace(stored, torrent, &admin_token);
+        let response = match self.registry.insert(info_hash, torrent, may_replace) {
+            Ok(()) => {
+                println!(
+                    "Stored torrent of swarm {}, uploaded by {}",
+                    info_hash, source
+                );
+                crate::get_ok_response()
+            }
+            Err(reason) => {
+                println!(
+                    "Refusing torrent of swarm {} from {}: {}",
+                    info_hash, source, reason
+                );
+                crate::get_bad_response()
+            }
+        };
+        crate::send_message(stream, response).ok();
+    }
+
+    /// A stored torrent may be uploaded again as is, replaced by one of the
+    /// same publisher, both signatures being valid, or by anyone with the admin
+    /// token.
+    fn may_replace(&self, stored: &[u8], torrent: &[u8], admin_token: &str) -> Result<(), String> {
+        if stored == torrent {
+            return Ok(());
+        }
+        if let Some(token) = &self.config.admin_token {
+            if admin::constant_time_eq(admin_token.as_bytes(), token.as_bytes()) {
+                return Ok(());
+            }
+        }
+        let stored = signing::signer(stored)
+            .map_err(|e| format!("only the admin may replace the stored one: {}", e))?;
+        match signing::signer(torrent) {
+            Ok(signer) if signer == stored => Ok(()),
+            Ok(_) => Err("it is signed by another publisher".to_string()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    pub(super) fn handle_fetch_torrent_request(
+        &mut self,
+        stream: &mut TcpStream,
+        info_hash: InfoHash,
+        passkey: &str,
+    ) {
+        let torrent = match self.access.check(info_hash, passkey) {
+            Ok(_) => self.registry.get(info_hash),
+            Err(_) => None,
+        };
+        let response = match torrent {
+            Some(torrent) => crate::get_torrent_response(torrent),
+            None => crate::get_bad_response(),
+        };
+        crate::send_message(stream, response).ok();
+    }
+}
@@ -0,0 +1,356 @@
+//! The tracker side of the UDP tracker protocol (BEP 15).
+//!
+//! Connection ids are not stored: each one is a hash of the client IP and the
+//! current minute, keyed by a secret of the tracker, so that a client spoofing
+//! its source address never learns one it could announce with.
+//!
+//! Announces may carry the URL data option of BEP 41, from which the tracker
+//! reads the `passkey` query parameter.
+
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use sha2::{Digest, Sha256};
+
+use super::Tracker;
+use crate::announce::Announce;
+use crate::peer::PeerId;
+use crate::requests::request::{Event, Transfer};
+use crate::torrent::InfoHash;
+use crate::udp_tracker::{
+    encode_compact_peer, ACTION_ANNOUNCE, ACTION_CONNECT, ACTION_ERROR, ACTION_SCRAPE,
+    MAX_SCRAPE_HASHES, OPTION_END, OPTION_NOP, OPTION_URL_DATA, PROTOCOL_ID,
+};
+
+/// Connection ids are valid for the minute they are handed out in and the
+/// next one.
+const CONNECTION_WINDOW_SECS: u64 = 60;
+const ANNOUNCE_LENGTH: usize = 98;
+
+pub(super) fn serve(socket: UdpSocket, tracker: Tracker) {
+    let mut buffer = [0; 2048];
+    loop {
+        let (length, source) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        if let Some(answer) = tracker.handle_udp_packet(&buffer[..length], source) {
+            socket.send_to(&answer, source).ok();
+        }
+    }
+}
+
+impl Tracker {
+    /// The answer to a packet, none for one too short to be answered or from
+    /// a source over its limits.
+    fn handle_udp_packet(&self, packet: &[u8], source: SocketAddr) -> Option<Vec<u8>> {
+        if let Err(reason) = self.limits.admit_request(source.ip()) {
+            self.metrics.refused.inc(reason);
+            return None;
+        }
+        if packet.len() < 16 {
+            self.metrics.requests.inc("udp_malformed");
+            return None;
+        }
+        let connection_id = u64::from_be_bytes(packet[0..8].try_into().unwrap());
+        let action = u32::from_be_bytes(packet[8..12].try_into().unwrap());
+        let transaction_id = u32::from_be_bytes(packet[12..16].try_into().unwrap());
+        let body = &packet[16..];
+
+        let answer = match action {
+            ACTION_CONNECT if connection_id == PROTOCOL_ID => {
+                self.metrics.requests.inc("udp_connect");
+                let mut answer = vec![];
+                answer
+                    .write_u64::<NetworkEndian>(self.connection_id(source.ip(), current_window()))
+                    .unwrap();
+                Ok(answer)
+            }
+            ACTION_ANNOUNCE | ACTION_SCRAPE if !self.valid_connection_id(connection_id, source) => {
+                self.metrics.requests.inc("udp_malformed");
+                Err("bad connection id")
+            }
+            ACTION_ANNOUNCE if body.len() + 16 >= ANNOUNCE_LENGTH => {
+                self.metrics.requests.inc("udp_announce");
+                self.handle_udp_announce(body, source)
+            }
+            ACTION_SCRAPE if !body.is_empty() && body.len().is_multiple_of(20) => {
+                self.metrics.requests.inc("udp_scrape");
+                self.handle_udp_scrape(body)
+            }
+            _ => {
+                self.metrics.requests.inc("udp_malformed");
+                Err("bad request")
+            }
+        };
+
+        let mut packet = vec![];
+        match answer {
+            Ok(answer) => {
+                packet.write_u32::<NetworkEndian>(action).unwrap();
+                packet.write_u32::<NetworkEndian>(transaction_id).unwrap();
+                packet.extend_from_slice(&answer);
+            }
+            Err(reason) => {
+                println!("Rejecting UDP request from {}: {}", source, reason);
+                packet.write_u32::<NetworkEndian>(ACTION_ERROR).unwrap();
+                packet.write_u32::<NetworkEndian>(transaction_id).unwrap();
+                packet.extend_from_slice(reason.as_bytes());
+            }
+        }
+        Some(packet)
+    }
+
+    fn handle_udp_announce(
+        &self,
+        body: &[u8],
+        source: SocketAddr,
+    ) -> Result<Vec<u8>, &'static str> {
+        let info_hash = InfoHash::from_bytes(&body[0..20]).unwrap();
+        let peer_id = PeerId::from_bytes(&body[20..40]).unwrap();
+        let mut fields = &body[40..];
+        let downloaded = fields.read_u64::<NetworkEndian>().unwrap();
+        let left = fields.read_u64::<NetworkEndian>().unwrap();
+        let uploaded = fields.read_u64::<NetworkEndian>().unwrap();
+        let event = fields.read_i32::<NetworkEndian>().unwrap();
+        let ip = fields.read_u32::<NetworkEndian>().unwrap();
+        let _key = fields.read_u32::<NetworkEndian>().unwrap();
+        let numwant = fields.read_i32::<NetworkEndian>().unwrap();
+        let port = fields.read_u16::<NetworkEndian>().unwrap();
+
+        let event = Event::from_i32(event).ok_or("bad event")?;
+        // as over TCP, the source IP unless the client is trusted to give its own
+        let client_addr = if self.config.trust_client_addr && ip != 0 {
+            SocketAddr::new(Ipv4Addr::from(ip).into(), port)
+        } else {
+            SocketAddr::new(source.ip(), port)
+        };
+        println!(
+            "Handling UDP announce from {} for swarm {}, peer {} listening at {}",
+            source, info_hash, peer_id, client_addr
+        );
+        let transfer = Transfer {
+            uploaded,
+            downloaded,
+            left,
+        };
+        let passkey = passkey(&body[ANNOUNCE_LENGTH - 16..]);
+        self.announce(Announce {
+            listening_addr: client_addr,
+            info_hash,
+            peer_id,
+            event,
+            transfer,
+            passkey,
+        })?;
+
+        let peers = if event == Event::Stopped {
+            vec![]
+        } else {
+            // -1 leaves the count to the tracker, as 0 does over TCP; and the
+            // compact addresses are those of the family of the client only
+            let numwant = numwant.max(0) as usize;
+            self.sample_peers(info_hash, Some(peer_id), numwant, source.ip(), |addr| {
+                addr.is_ipv4() == source.is_ipv4()
+            })
+        };
+        let stats = self.swarm_stats(vec![info_hash]).remove(0);
+
+        let mut answer = vec![];
+        let interval = self.config.interval.as_secs().max(1) as u32;
+        answer.write_u32::<NetworkEndian>(interval).unwrap();
+        answer.write_u32::<NetworkEndian>(stats.leechers).unwrap();
+        answer.write_u32::<NetworkEndian>(stats.seeders).unwrap();
+        for (_, addr, _) in peers {
+            answer.extend_from_slice(&encode_compact_peer(addr));
+        }
+        Ok(answer)
+    }
+
+    fn handle_udp_scrape(&self, body: &[u8]) -> Result<Vec<u8>, &'static str> {
+        let info_hashes: Vec<InfoHash> = body
+            .chunks(20)
+            .take(MAX_SCRAPE_HASHES)
+            .map(|info_hash| InfoHash::from_bytes(info_hash).unwrap())
+            .collect();
+        // a scrape carries no options, so it only passes without users
+        let info_hashes = self.scrape_scope(info_hashes, "")?;
+        let mut answer = vec![];
+        for stats in self.swarm_stats(info_hashes) {
+            answer.write_u32::<NetworkEndian>(stats.seeders).unwrap();
+            answer
+                .write_u32::<NetworkEndian>(stats.completed as u32)
+                .unwrap();
+            answer.write_u32::<NetworkEndian>(stats.leechers).unwrap();
+        }
+        Ok(answer)
+    }
+
+    fn connection_id(&self, ip: IpAddr, window: u64) -> u64 {
+        let mut hasher = Sha256::new();
+        hasher.update(self.udp_secret);
+        match ip {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.update(window.to_be_bytes());
+        u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap())
+    }
+
+    fn valid_connection_id(&self, connection_id: u64, source: SocketAddr) -> bool {
+        let window = current_window();
+        connection_id == self.connection_id(source.ip(), window)
+            || connection_id == self.connection_id(source.ip(), window.saturating_sub(1))
+    }
+}
+
+/// The `passkey` query parameter of the URL data of the announce options,
+/// empty without one.
+fn passkey(mut options: &[u8]) -> String {
+    let mut url_data = vec![];
+    while let Some((&option, rest)) = options.split_first() {
+        options = rest;
+        match option {
+            OPTION_END => break,
+            OPTION_NOP => continue,
+            _ => {
+                let length = match options.first() {
+                    Some(&length) => length as usize,
+                    None => break,
+                };
+                let data = match options.get(1..1 + length) {
+                    Some(data) => data,
+                    None => break,
+                };
+                if option == OPTION_URL_DATA {
+                    url_data.extend_from_slice(data);
+                }
+                options = &options[1 + length..];
+            }
+        }
+    }
+    let url_data = String::from_utf8_lossy(&url_data);
+    let query = url_data.split_once('?').map_or("", |(_, query)| query);
+    query
+        .split('&')
+        .find_map(|param| param.strip_prefix("passkey="))
+        .unwrap_or("")
+        .to_string()
+}
+
+fn current_window() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_secs() / CONNECTION_WINDOW_SECS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: &str = "127.0.0.1:40000";
+
+    fn packet(connection_id: u64, action: u32, body: &[u8]) -> Vec<u8> {
+        let mut packet = vec![];
+        packet.write_u64::<NetworkEndian>(connection_id).unwrap();
+        packet.write_u32::<NetworkEndian>(action).unwrap();
+        packet.write_u32::<NetworkEndian>(7).unwrap();
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    /// Split an answer into its action and body, checking the transaction id.
+    fn answer(answer: Option<Vec<u8>>) -> (u32, Vec<u8>) {
+        let answer = answer.unwrap();
+        assert_eq!(&answer[4..8], &7u32.to_be_bytes());
+        let action = u32::from_be_bytes(answer[0..4].try_into().unwrap());
+        (action, answer[8..].to_vec())
+    }
+
+    fn connect(tracker: &Tracker, source: SocketAddr) -> u64 {
+        let connect = packet(PROTOCOL_ID, ACTION_CONNECT, &[]);
+        let (action, body) = answer(tracker.handle_udp_packet(&connect, source));
+        assert_eq!(action, ACTION_CONNECT);
+        u64::from_be_bytes(body[..].try_into().unwrap())
+    }
+
+    fn announce_body(peer_id: u8, port: u16, options: &[u8]) -> Vec<u8> {
+        let mut body = vec![1; 20];
+        body.extend_from_slice(&[peer_id; 20]);
+        body.write_u64::<NetworkEndian>(0).unwrap();
+        body.write_u64::<NetworkEndian>(100).unwrap();
+        body.write_u64::<NetworkEndian>(0).unwrap();
+        body.write_u32::<NetworkEndian>(2).unwrap();
+        body.write_u32::<NetworkEndian>(0).unwrap();
+        body.write_u32::<NetworkEndian>(0).unwrap();
+        body.write_i32::<NetworkEndian>(-1).unwrap();
+        body.write_u16::<NetworkEndian>(port).unwrap();
+        body.extend_from_slice(options);
+        body
+    }
+
+    #[test]
+    fn connect_then_announce() {
+        let tracker = Tracker::new();
+        let source: SocketAddr = CLIENT.parse().unwrap();
+        let connection_id = connect(&tracker, source);
+
+        let first = packet(connection_id, ACTION_ANNOUNCE, &announce_body(2, 7000, &[]));
+        let (action, _) = answer(tracker.handle_udp_packet(&first, source));
+        assert_eq!(action, ACTION_ANNOUNCE);
+
+        let second = packet(connection_id, ACTION_ANNOUNCE, &announce_body(3, 7001, &[]));
+        let (action, body) = answer(tracker.handle_udp_packet(&second, source));
+        assert_eq!(action, ACTION_ANNOUNCE);
+        let mut fields = &body[..];
+        assert_eq!(fields.read_u32::<NetworkEndian>().unwrap(), 2);
+        // leechers, then seeders, then the other peer at the source IP
+        assert_eq!(fields.read_u32::<NetworkEndian>().unwrap(), 2);
+        assert_eq!(fields.read_u32::<NetworkEndian>().unwrap(), 0);
+        assert_eq!(fields, &[127, 0, 0, 1, 0x1b, 0x58]);
+
+        let scrape = packet(connection_id, ACTION_SCRAPE, &[1; 20]);
+        let (action, body) = answer(tracker.handle_udp_packet(&scrape, source));
+        assert_eq!(action, ACTION_SCRAPE);
+        assert_eq!(body, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn refuses_bad_packets() {
+        let tracker = Tracker::new();
+        let source: SocketAddr = CLIENT.parse().unwrap();
+        assert_eq!(tracker.handle_udp_packet(&[0; 15], source), None);
+
+        let connection_id = connect(&tracker, source);
+        let other: SocketAddr = "127.0.0.2:40000".parse().unwrap();
+        let announce = packet(connection_id, ACTION_ANNOUNCE, &announce_body(2, 7000, &[]));
+        let (action, body) = answer(tracker.handle_udp_packet(&announce, other));
+        assert_eq!(action, ACTION_ERROR);
+        assert_eq!(body, b"bad connection id");
+
+        let short = packet(connection_id, ACTION_ANNOUNCE, &[0; 20]);
+        let (action, body) = answer(tracker.handle_udp_packet(&short, source));
+        assert_eq!(action, ACTION_ERROR);
+        assert_eq!(body, b"bad request");
+
+        let scrape = packet(connection_id, ACTION_SCRAPE, &[1; 21]);
+        assert_eq!(
+            answer(tracker.handle_udp_packet(&scrape, source)).0,
+            ACTION_ERROR
+        );
+    }
+
+    #[test]
+    fn passkey_of_the_url_data() {
+        let mut options = vec![OPTION_NOP, OPTION_URL_DATA, 18];
+        options.extend_from_slice(b"/announce?passkey=");
+        options.extend_from_slice(&[OPTION_URL_DATA, 9]);
+        options.extend_from_slice(b"abc&x=yz1");
+        options.push(OPTION_END);
+        assert_eq!(passkey(&options), "abc");
+        assert_eq!(passkey(&[]), "");
+        assert_eq!(passkey(&[OPTION_URL_DATA, 200, b'/']), "");
+        assert_eq!(passkey(b"\x02\x0a/announce?"), "");
+    }
+}
@@ -0,0 +1,335 @@
+//! The UDP tracker protocol of BEP 15, as spoken by peers to a tracker.
+//!
+//! A client first gets a connection id, proving it can receive at its
+//! source address, then announces or scrapes with it for a couple of minutes.
+
+use std::convert::TryInto;
+use std::io::{self, Cursor, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::announce::Announce;
+use crate::responses::response::SwarmStats;
+use crate::torrent::InfoHash;
+
+pub const PROTOCOL_ID: u64 = 0x0417_2710_1980;
+pub const ACTION_CONNECT: u32 = 0;
+pub const ACTION_ANNOUNCE: u32 = 1;
+pub const ACTION_SCRAPE: u32 = 2;
+pub const ACTION_ERROR: u32 = 3;
+/// Options that may follow an announce (BEP 41).
+pub const OPTION_END: u8 = 0;
+pub const OPTION_NOP: u8 = 1;
+pub const OPTION_URL_DATA: u8 = 2;
+
+/// How long a client reuses a connection id. Trackers accept one for at
+/// least two minutes.
+const CONNECTION_LIFETIME: Duration = Duration::from_secs(60);
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// Info hashes a single scrape may carry.
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+/// Connection ids this process holds, by tracker.
+static CONNECTIONS: Mutex<Vec<(SocketAddr, u64, Instant)>> = Mutex::new(Vec::new());
+
+pub struct AnnounceAnswer {
+    pub interval: Duration,
+    pub peers: Vec<SocketAddr>,
+}
+
+pub fn announce(
+    tracker: SocketAddr,
+    announce: &Announce,
+    numwant: u32,
+) -> io::Result<AnnounceAnswer> {
+    let mut request = vec![];
+    request.extend_from_slice(&announce.info_hash.0);
+    request.extend_from_slice(&announce.peer_id.0);
+    request.write_u64::<NetworkEndian>(announce.transfer.downloaded)?;
+    request.write_u64::<NetworkEndian>(announce.transfer.left)?;
+    request.write_u64::<NetworkEndian>(announce.transfer.uploaded)?;
+    // the events of BEP 15 are numbered as ours
+    request.write_u32::<NetworkEndian>(announce.event as u32)?;
+    // no IP of our own, the tracker takes the source address
+    request.write_u32::<NetworkEndian>(0)?;
+    // key
+    request.write_u32::<NetworkEndian>(0)?;
+    let numwant = if numwant == 0 { -1 } else { numwant as i32 };
+    request.write_i32::<NetworkEndian>(numwant)?;
+    request.write_u16::<NetworkEndian>(announce.listening_addr.port())?;
+    if !announce.passkey.is_empty() {
+        // the passkey travels as the query of the announce URL
+        let url_data = format!("/announce?passkey={}", announce.passkey);
+        for part in url_data.as_bytes().chunks(255) {
+            request.push(OPTION_URL_DATA);
+            request.push(part.len() as u8);
+            request.extend_from_slice(part);
+        }
+        request.push(OPTION_END);
+    }
+
+    let answer = exchange(tracker, ACTION_ANNOUNCE, &request)?;
+    let mut answer = Cursor::new(answer);
+    let interval = answer.read_u32::<NetworkEndian>()?;
+    let _leechers = answer.read_u32::<NetworkEndian>()?;
+    let _seeders = answer.read_u32::<NetworkEndian>()?;
+    let mut peers = vec![];
+    let mut compact = vec![];
+    answer.read_to_end(&mut compact)?;
+    // the address family of the tracker decides the one of the peers
+    let entry_length = if tracker.is_ipv4() { 6 } else { 18 };
+    for entry in compact.chunks_exact(entry_length) {
+        peers.push(decode_compact_peer(entry));
+    }
+    Ok(AnnounceAnswer {
+        interval: Duration::from_secs(u64::from(interval.max(1))),
+        peers,
+    })
+}
+
+pub fn scrape(tracker: SocketAddr, info_hashes: &[InfoHash]) -> io::Result<Vec<SwarmStats>> {
+    if info_hashes.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a UDP tracker can only scrape named swarms",
+        ));
+    }
+    let mut stats = vec![];
+    for info_hashes in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+        let request: Vec<u8> = info_hashes.iter().flat_map(|h| h.0.to_vec()).collect();
+        let answer = exchange(tracker, ACTION_SCRAPE, &request)?;
+        let mut answer = Cursor::new(answer);
+        for info_hash in info_hashes {
+            let seeders = answer.read_u32::<NetworkEndian>()?;
+            let completed = answer.read_u32::<NetworkEndian>()?;
+            let leechers = answer.read_u32::<NetworkEndian>()?;
+            stats.push(SwarmStats {
+                info_hash: info_hash.0.to_vec(),
+                seeders,
+                leechers,
+                completed: u64::from(completed),
+                ..Default::default()
+            });
+        }
+    }
+    Ok(stats)
+}
+
+/// Send `request` with a connection id, connecting first if there is no live
+/// one, and return the answer past its action and transaction id. A rejected
+/// connection id is renewed once.
+fn exchange(tracker: SocketAddr, action: u32, request: &[u8]) -> io::Result<Vec<u8>> {
+    let socket = bind_for(tracker)?;
+    let cached = cached_connection_id(tracker);
+    let connection_id = match cached {
+        Some(connection_id) => connection_id,
+        None => connect(&socket, tracker)?,
+    };
+    match transact(&socket, connection_id, action, request) {
+        Err(_) if cached.is_some() => {
+            let connection_id = connect(&socket, tracker)?;
+            transact(&socket, connection_id, action, request)
+        }
+        answer => answer,
+    }
+}
+
+fn bind_for(tracker: SocketAddr) -> io::Result<UdpSocket> {
+    let local: SocketAddr = if tracker.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(tracker)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    Ok(socket)
+}
+
+fn cached_connection_id(tracker: SocketAddr) -> Option<u64> {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    connections.retain(|(_, _, since)| since.elapsed() < CONNECTION_LIFETIME);
+    connections
+        .iter()
+        .find(|(addr, _, _)| *addr == tracker)
+        .map(|(_, connection_id, _)| *connection_id)
+}
+
+fn connect(socket: &UdpSocket, tracker: SocketAddr) -> io::Result<u64> {
+    let answer = transact(socket, PROTOCOL_ID, ACTION_CONNECT, &[])?;
+    let connection_id = Cursor::new(answer).read_u64::<NetworkEndian>()?;
+    let mut connections = CONNECTIONS.lock().unwrap();
+    connections.retain(|(addr, _, _)| *addr != tracker);
+    connections.push((tracker, connection_id, Instant::now()));
+    Ok(connection_id)
+}
+
+fn transact(
+    socket: &UdpSocket,
+    connection_id: u64,
+    action: u32,
+    body: &[u8],
+) -> io::Result<Vec<u8>> {
+    let transaction_id: u32 = rand::random();
+    let mut packet = vec![];
+    packet.write_u64::<NetworkEndian>(connection_id)?;
+    packet.write_u32::<NetworkEndian>(action)?;
+    packet.write_u32::<NetworkEndian>(transaction_id)?;
+    packet.extend_from_slice(body);
+    socket.send(&packet)?;
+
+    let mut buffer = vec![0; 65536];
+    loop {
+        let length = socket.recv(&mut buffer)?;
+        let mut answer = Cursor::new(&buffer[..length]);
+        let answered_action = answer.read_u32::<NetworkEndian>()?;
+        if answer.read_u32::<NetworkEndian>()? != transaction_id {
+            // a late answer to an earlier request
+            continue;
+        }
+        let rest = buffer[8..length].to_vec();
+        return match answered_action {
+            a if a == action => Ok(rest),
+            ACTION_ERROR => Err(io::Error::other(
+                String::from_utf8_lossy(&rest).into_owned(),
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected answer from UDP tracker",
+            )),
+        };
+    }
+}
+
+/// A peer address as packed in an announce answer: the IP then the port.
+pub fn encode_compact_peer(addr: SocketAddr) -> Vec<u8> {
+    let mut entry = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    entry.extend_from_slice(&addr.port().to_be_bytes());
+    entry
+}
+
+fn decode_compact_peer(entry: &[u8]) -> SocketAddr {
+    let (ip, port) = entry.split_at(entry.len() - 2);
+    let ip: IpAddr = match ip.len() {
+        4 => {
+            let octets: [u8; 4] = ip.try_into().unwrap();
+            octets.into()
+        }
+        _ => {
+            let octets: [u8; 16] = ip.try_into().unwrap();
+            octets.into()
+        }
+    };
+    SocketAddr::new(ip, u16::from_be_bytes(port.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::PeerId;
+    use crate::requests::request::{Event, Transfer};
+
+    #[test]
+    fn compact_peers_round_trip() {
+        for addr in ["10.0.0.1:6881", "[2001:db8::1]:443"].iter() {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let entry = encode_compact_peer(addr);
+            assert_eq!(entry.len(), if addr.is_ipv4() { 6 } else { 18 });
+            assert_eq!(decode_compact_peer(&entry), addr);
+        }
+        let addr = "1.2.3.4:258".parse().unwrap();
+        assert_eq!(encode_compact_peer(addr), [1, 2, 3, 4, 1, 2]);
+    }
+
+    /// Answer a connect then an announce the way a tracker would, checking
+    /// the packets sent, and return the announce body.
+    fn fake_tracker(answer: Vec<u8>) -> (SocketAddr, std::thread::JoinHandle<Vec<u8>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut buffer = [0; 2048];
+            let (length, client) = socket.recv_from(&mut buffer).unwrap();
+            assert_eq!(length, 16);
+            assert_eq!(&buffer[0..8], &PROTOCOL_ID.to_be_bytes());
+            assert_eq!(&buffer[8..12], &ACTION_CONNECT.to_be_bytes());
+            let mut packet = ACTION_CONNECT.to_be_bytes().to_vec();
+            packet.extend_from_slice(&buffer[12..16]);
+            packet.extend_from_slice(&42u64.to_be_bytes());
+            socket.send_to(&packet, client).unwrap();
+
+            let (length, client) = socket.recv_from(&mut buffer).unwrap();
+            assert_eq!(&buffer[0..8], &42u64.to_be_bytes());
+            assert_eq!(&buffer[8..12], &ACTION_ANNOUNCE.to_be_bytes());
+            let mut packet = ACTION_ANNOUNCE.to_be_bytes().to_vec();
+            packet.extend_from_slice(&buffer[12..16]);
+            packet.extend_from_slice(&answer);
+            socket.send_to(&packet, client).unwrap();
+            buffer[16..length].to_vec()
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn announce_packets() {
+        let mut answer = vec![];
+        answer.write_u32::<NetworkEndian>(30).unwrap();
+        answer.write_u32::<NetworkEndian>(1).unwrap();
+        answer.write_u32::<NetworkEndian>(2).unwrap();
+        answer.extend(encode_compact_peer("10.0.0.1:6881".parse().unwrap()));
+        answer.extend(encode_compact_peer("10.0.0.2:6882".parse().unwrap()));
+        let (tracker, fake) = fake_tracker(answer);
+
+        let query = Announce {
+            listening_addr: "127.0.0.1:7000".parse().unwrap(),
+            info_hash: InfoHash([1; 20]),
+            peer_id: PeerId([2; 20]),
+            event: Event::Completed,
+            transfer: Transfer {
+                uploaded: 3,
+                downloaded: 4,
+                left: 5,
+            },
+            passkey: "secret".to_string(),
+        };
+        let answer = announce(tracker, &query, 0).unwrap();
+        assert_eq!(answer.interval, Duration::from_secs(30));
+        assert_eq!(
+            answer.peers,
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:6882".parse().unwrap()
+            ]
+        );
+
+        let body = fake.join().unwrap();
+        let mut fields = Cursor::new(&body[40..]);
+        assert_eq!(&body[0..20], &[1; 20]);
+        assert_eq!(&body[20..40], &[2; 20]);
+        assert_eq!(fields.read_u64::<NetworkEndian>().unwrap(), 4);
+        assert_eq!(fields.read_u64::<NetworkEndian>().unwrap(), 5);
+        assert_eq!(fields.read_u64::<NetworkEndian>().unwrap(), 3);
+        assert_eq!(fields.read_u32::<NetworkEndian>().unwrap(), 1);
+        assert_eq!(fields.read_u32::<NetworkEndian>().unwrap(), 0);
+        assert_eq!(fields.read_u32::<NetworkEndian>().unwrap(), 0);
+        assert_eq!(fields.read_i32::<NetworkEndian>().unwrap(), -1);
+        assert_eq!(fields.read_u16::<NetworkEndian>().unwrap(), 7000);
+        let url_data = b"/announce?passkey=secret";
+        let mut options = vec![OPTION_URL_DATA, url_data.len() as u8];
+        options.extend_from_slice(url_data);
+        options.push(OPTION_END);
+        assert_eq!(&body[82..], &options[..]);
+    }
+
+    #[test]
+    fn scrape_needs_info_hashes() {
+        let tracker = "127.0.0.1:9".parse().unwrap();
+        let error = scrape(tracker, &[]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}